@@ -1,6 +1,6 @@
-use nianjia::util::command_prelude::*;
-use nianjia::util::config::Config;
-use nianjia::util::errors::CliResult;
+use crate::util::command_prelude::*;
+use crate::util::config::Config;
+use crate::util::errors::CliResult;
 use clap::ArgMatches;
 
 pub fn builtin() -> Vec<App> {
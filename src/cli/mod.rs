@@ -0,0 +1,644 @@
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use clap::{AppSettings, ArgMatches};
+use serde_json::json;
+
+use crate::core::shell::{print_table, MessageFormat, Verbosity};
+use crate::util::config::Config;
+use crate::util::process_builder;
+use crate::util::lev_distance::closest;
+use crate::util::command_prelude::*;
+use crate::util::errors::{display_causes, ProcessError, NianjiaResult, CliError, CliResult};
+
+mod commands;
+
+/// Runs the command line `args`, where `args[0]` is the binary name.
+pub fn main(config: &mut Config, args: &[String]) -> CliResult {
+    let args = match cli().get_matches_from_safe(args) {
+        Ok(args) => args,
+        Err(e) => {
+            // if e.kind == clap::ErrorKind::UnrecognizedSubcommand {
+            //     // An unrecognized subcommand might be an external subcommand.
+            //     let cmd = &e.info.as_ref().unwrap()[0].to_owned();
+            //     return execute_external_subcommand(config, cmd, &[cmd, "--help"])
+            //         .map_err(|_| e.into());
+            // } else {
+                return Err(e)?;
+            //}
+        }
+    };
+    
+    let is_verbose = args.occurrences_of("verbose") > 0;
+
+    if args.is_present("list") {
+        let commands = filter_commands(list_commands(config), args.value_of("filter"));
+        let json = args.value_of("message-format") == Some("json");
+        let width = if atty::is(atty::Stream::Stdout) {
+            config.term_width()
+        } else {
+            None
+        };
+        let stdout = io::stdout();
+        write_command_list(&mut stdout.lock(), commands, json, is_verbose, width)?;
+        return Ok(());
+    }
+
+    let args = expand_aliases(config, args, &mut Vec::new())?;
+
+    execute_subcommand(config, &args)
+}
+
+/// Keeps the commands whose name contains `filter`, if given (`--filter`).
+fn filter_commands<'a>(
+    commands: BTreeSet<CommandInfo>,
+    filter: Option<&'a str>,
+) -> impl Iterator<Item = CommandInfo> + 'a {
+    commands
+        .into_iter()
+        .filter(move |command| match filter {
+            Some(f) => command.name().contains(f),
+            None => true,
+        })
+}
+
+/// Writes the `--list` output, either for humans or, with
+/// `--message-format json`, as a JSON array.
+fn write_command_list(
+    out: &mut dyn Write,
+    commands: impl Iterator<Item = CommandInfo>,
+    json: bool,
+    is_verbose: bool,
+    width: Option<usize>,
+) -> NianjiaResult<()> {
+    if json {
+        let list = commands
+            .map(|command| match command {
+                CommandInfo::BuiltIn { name, about } => {
+                    json!({ "name": name, "kind": "builtin", "about": about })
+                }
+                CommandInfo::External { name, path } => {
+                    json!({ "name": name, "kind": "external", "path": path })
+                }
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_writer(&mut *out, &list)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+
+    let rows = commands
+        .map(|command| match command {
+            CommandInfo::BuiltIn { name, about } => {
+                let about = about.unwrap_or_default();
+                let summary = about.lines().next().unwrap_or_default(); // display only the first line
+                (name, summary.to_string())
+            }
+            CommandInfo::External { name, path } => {
+                if is_verbose {
+                    (name, path.display().to_string())
+                } else {
+                    (name, String::new())
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    writeln!(out, "Installed Commands:")?;
+    print_table(out, &rows, width)?;
+    Ok(())
+}
+
+/// Replaces an aliased subcommand with its expansion, repeatedly.
+/// `already_expanded` holds the aliases expanded so far, to reject an alias
+/// that expands to itself.
+fn expand_aliases(
+    config: &mut Config,
+    args: ArgMatches<'static>,
+    already_expanded: &mut Vec<String>,
+) -> Result<ArgMatches<'static>, CliError> {
+    if let (cmd, Some(args)) = args.subcommand() {
+        match (
+            commands::builtin_exec(cmd),
+            aliased_command(config, cmd)?,
+        ) {
+            (Some(_), Some(_)) => {
+                // User alias conflicts with a built-in subcommand
+                config.shell().warn(format!(
+                    "user-defined alias `{}` is ignored, because it is shadowed by a built-in command",
+                    cmd,
+                ))?;
+            }
+            (_, Some(mut alias)) => {
+                if already_expanded.iter().any(|name| name == cmd) {
+                    let mut chain = already_expanded.clone();
+                    chain.push(cmd.to_string());
+                    return Err(failure::format_err!(
+                        "alias `{}` expands to itself: {}",
+                        cmd,
+                        chain.join(" -> ")
+                    )
+                    .into());
+                }
+                already_expanded.push(cmd.to_string());
+                alias.extend(
+                    args.values_of("")
+                        .unwrap_or_default()
+                        .map(|s| s.to_string()),
+                );
+                let args = cli()
+                    .setting(AppSettings::NoBinaryName)
+                    .get_matches_from_safe(alias)?;
+                return expand_aliases(config, args, already_expanded);
+            }
+            (_, None) => {}
+        }
+    };
+
+    Ok(args)
+}
+
+fn execute_subcommand(config: &mut Config, args: &ArgMatches<'_>) -> CliResult {
+    let (cmd, subcommand_args) = match args.subcommand() {
+        (cmd, Some(args)) => (cmd, args),
+        _ => {
+            cli().print_help()?;
+            return Ok(());
+        }
+    };
+
+    let arg_target_dir = &subcommand_args.value_of_path("target-dir", config);
+
+    let quiet = args
+        .maybe_flag("quiet")
+        .or_else(|| subcommand_args.maybe_flag("quiet"));
+    // Respect the command line while loading the configuration, so its
+    // warnings are summarized or shown in full as requested.
+    config.shell().set_verbosity(match (args.occurrences_of("verbose"), quiet) {
+        (0, Some(true)) => Verbosity::Quiet,
+        (0, _) => Verbosity::Normal,
+        _ => Verbosity::Verbose,
+    });
+    if args.value_of("message-format") == Some("json") {
+        config.shell().set_message_format(MessageFormat::Json);
+    }
+    config.set_trace_config(args.is_present("trace-config"));
+    config.set_extra_verbose(args.occurrences_of("verbose") >= 2);
+    config.set_stop_at_project_root(args.is_present("stop-at-project-root"));
+    config.set_dry_run(args.is_present("dry-run"));
+    if let Some(files) = args.values_of_os("config-file") {
+        config.set_config_files(&files.map(PathBuf::from).collect::<Vec<_>>())?;
+    }
+    config.load_dotenv(args.is_present("load-dotenv"), false)?;
+
+    config.configure(
+        args.occurrences_of("verbose") as u32,
+        quiet,
+        &args.value_of("color").map(|s| s.to_string()),
+        args.is_present("frozen"),
+        args.is_present("locked"),
+        arg_target_dir,
+        &args
+            .values_of_lossy("unstable-features")
+            .unwrap_or_default(),
+        &args.values_of_lossy("config").unwrap_or_default(),
+        args.value_of_u32("jobs")?,
+    )?;
+    config
+        .shell()
+        .set_assume_yes(args.is_present("yes") || subcommand_args.is_present("yes"));
+
+    let result = if let Some(exec) = commands::builtin_exec(cmd) {
+        exec(config, subcommand_args)
+    } else {
+        let mut ext_args: Vec<&str> = vec![cmd];
+        ext_args.extend(subcommand_args.values_of("").unwrap_or_default());
+        execute_external_subcommand(config, cmd, &ext_args)
+    };
+
+    if config.trace_config() {
+        for key in config.consulted_env_keys() {
+            drop(writeln!(
+                config.shell().err(),
+                "consulted environment variable `{}`",
+                key
+            ));
+        }
+    }
+
+    result
+}
+
+fn cli() -> App {
+  App::new("nianjia")
+        .settings(&[
+            AppSettings::UnifiedHelpMessage,
+            AppSettings::DeriveDisplayOrder,
+            AppSettings::VersionlessSubcommands,
+            AppSettings::AllowExternalSubcommands,
+        ])
+        .about("")
+        .template(
+            "\
+Nianjia, the Sandboxing Environment for Next Generation Computation
+
+USAGE:
+    {usage}
+
+OPTIONS:
+{unified}
+
+Some common nianjia commands are (see all commands with --list):
+    run         Run a binary or example of the local package
+
+See 'nianjia help <command>' for more information on a specific command.\n",
+        )
+        .arg(opt("version", "Print version info and exit").short("V"))
+        .arg(opt("list", "List installed commands"))
+        .arg(
+            opt("filter", "Only list commands whose name contains PATTERN")
+                .value_name("PATTERN")
+                .requires("list"),
+        )
+        .arg(
+            opt(
+                "verbose",
+                "Use verbose output (-vv very verbose output)",
+            )
+            .short("v")
+            .multiple(true)
+            .global(true),
+        )
+        .arg(opt("quiet", "No output printed to stdout").short("q"))
+        .arg(
+            opt("yes", "Assume yes to all confirmation prompts")
+                .short("y")
+                .global(true),
+        )
+        .arg(opt(
+            "trace-config",
+            "Print the environment variables consulted for configuration",
+        ))
+        .arg(opt(
+            "stop-at-project-root",
+            "Don't read configuration from above the project root",
+        ))
+        .arg(opt("dry-run", "Print the processes that would run without running them"))
+        .arg(opt(
+            "load-dotenv",
+            "Load environment variables from the nearest `.env` file",
+        ))
+        .arg(
+            opt("color", "Coloring: auto, always, never")
+                .value_name("WHEN")
+                .global(true),
+        )
+        .arg(
+            opt("jobs", "Number of parallel jobs, defaults to # of CPUs")
+                .short("j")
+                .value_name("N")
+                .global(true),
+        )
+        .arg(
+            opt("config-file", "Read configuration from FILE, overriding the discovered files")
+                .value_name("FILE")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            opt("config", "Override a configuration value")
+                .value_name("KEY=VALUE")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            opt("message-format", "Error format: human, json")
+                .value_name("FMT")
+                .possible_values(&["human", "json"])
+                .global(true),
+        )
+        .subcommands(commands::builtin())
+}
+
+/// Gets the expansion of `command` from `[alias]`, or a built-in alias.
+///
+/// A string alias, `b = "build --release"`, is split on whitespace. A list
+/// alias, `b = ["build", "--features=a b"]`, is used as is, one argument per
+/// element, so elements can hold spaces or `=` without further quoting.
+fn aliased_command(config: &Config, command: &str) -> NianjiaResult<Option<Vec<String>>> {
+    let alias_name = format!("alias.{}", command);
+    let user_alias = match config.get_string(&alias_name) {
+        Ok(Some(record)) => Some(
+            record
+                .val
+                .split_whitespace()
+                .map(|s| s.to_string())
+                .collect(),
+        ),
+        Ok(None) => None,
+        Err(_) => config
+            .get_list(&alias_name)?
+            .map(|record| record.val.iter().map(|s| s.0.to_string()).collect()),
+    };
+    let result = user_alias.or_else(|| match command {
+        "b" => Some(vec!["build".to_string()]),
+        "c" => Some(vec!["check".to_string()]),
+        "r" => Some(vec!["run".to_string()]),
+        "t" => Some(vec!["test".to_string()]),
+        _ => None,
+    });
+    Ok(result)
+}
+
+/// Lists the built-in commands and the `nianjia-<name>` executables in
+/// `search_directories`. A built-in command hides an executable of the same
+/// name, and an executable hides those of the same name in later
+/// directories.
+fn list_commands(config: &Config) -> BTreeSet<CommandInfo> {
+    let prefix = "nianjia-";
+    let suffix = env::consts::EXE_SUFFIX;
+    let mut commands = BTreeSet::new();
+    let mut names = BTreeSet::new();
+
+    for cmd in commands::builtin() {
+        names.insert(cmd.get_name().to_string());
+        commands.insert(CommandInfo::BuiltIn {
+            name: cmd.get_name().to_string(),
+            about: cmd.p.meta.about.map(|s| s.to_string()),
+        });
+    }
+
+    for dir in search_directories(config) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            _ => continue,
+        };
+        let mut entries = entries.filter_map(|e| e.ok()).collect::<Vec<_>>();
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = entry.path();
+            let filename = match path.file_name().and_then(|s| s.to_str()) {
+                Some(filename) => filename,
+                _ => continue,
+            };
+            if !filename.starts_with(prefix) || !filename.ends_with(suffix) {
+                continue;
+            }
+            let end = filename.len() - suffix.len();
+            let name = &filename[prefix.len()..end];
+            if !names.contains(name) && is_executable(&path) {
+                names.insert(name.to_string());
+                commands.insert(CommandInfo::External {
+                    name: name.to_string(),
+                    path: path.clone(),
+                });
+            }
+        }
+    }
+
+    commands
+}
+
+#[cfg(unix)]
+fn is_executable<P: AsRef<Path>>(path: P) -> bool {
+    use std::os::unix::prelude::*;
+    fs::metadata(path)
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+#[cfg(windows)]
+fn is_executable<P: AsRef<Path>>(path: P) -> bool {
+    fs::metadata(path)
+        .map(|metadata| metadata.is_file())
+        .unwrap_or(false)
+}
+
+
+fn search_directories(config: &Config) -> Vec<PathBuf> {
+    let mut dirs = vec![config.home().as_path_unlocked().join("bin")];
+    if let Some(val) = config.env_var("PATH") {
+        dirs.extend(env::split_paths(val));
+    }
+    dirs
+}
+
+fn find_closest(config: &Config, cmd: &str) -> Option<String> {
+    closest(cmd, list_commands(config).into_iter().map(|c| c.name()))
+}
+
+fn execute_external_subcommand(config: &Config, cmd: &str, args: &[&str]) -> CliResult {
+    let command_exe = format!("nianjia-{}{}", cmd, env::consts::EXE_SUFFIX);
+    let path = search_directories(config)
+        .iter()
+        .map(|dir| dir.join(&command_exe))
+        .find(|file| is_executable(file));
+    let command = match path {
+        Some(command) => command,
+        None => {
+            let err = match find_closest(config, cmd) {
+                Some(closest) => failure::format_err!(
+                    "no such subcommand: `{}`\n\n\tDid you mean `{}`?\n",
+                    cmd,
+                    closest
+                ),
+                None => failure::format_err!("no such subcommand: `{}`", cmd),
+            };
+            return Err(CliError::new(err, 101));
+        }
+    };
+
+    let mut process = process_builder::process(&command);
+    process.args(args);
+    // Unless the configuration can't be loaded at all, which nested nianjias
+    // report again, pass on its environment and resolved values.
+    match config.process_env() {
+        Ok(env) => process.inherit_env(&env),
+        Err(e) => {
+            let (causes, _) = display_causes(&e, false);
+            config.shell().warn(format!(
+                "running `nianjia {}` without the configured environment: {}{}",
+                cmd,
+                e,
+                causes.iter().map(|c| format!(": {}", c)).collect::<String>()
+            ))?;
+            process.env(crate::NIANJIA_ENV, config.nianjia_exe()?)
+        }
+    };
+    if let Ok(client) = config.jobserver() {
+        process.inherit_jobserver(client);
+    }
+    let err = match process.exec_replace() {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+
+    match err.downcast::<ProcessError>() {
+        Ok(perr) => match perr.exit.and_then(|status| status.code()) {
+            // The process reported its own failure already.
+            Some(code) => Err(CliError::code(code)),
+            // Killed by a signal, or never ran.
+            None => Err(CliError::from_process_error(perr, 101)),
+        },
+        Err(err) => Err(CliError::new(err, 101)),
+    }
+}
+
+#[test]
+fn run_from_library() {
+    use crate::core::shell::Shell;
+    use crate::util::flock::Filesystem;
+
+    let dir = env::temp_dir();
+    let mut config = Config::new(
+        Shell::from_write(Box::new(std::io::sink())),
+        dir.clone(),
+        Filesystem::new(dir),
+    );
+    assert!(crate::run(&["nianjia".to_string()], &mut config).is_ok());
+
+    let args = ["nianjia".to_string(), "--no-such-flag".to_string()];
+    let err = crate::run(&args, &mut config).unwrap_err();
+    assert_eq!(err.exit_code, 1);
+    assert!(err.error.unwrap().downcast_ref::<clap::Error>().is_some());
+}
+
+#[test]
+fn command_list_formats() {
+    let commands = vec![
+        CommandInfo::BuiltIn {
+            name: "build".to_string(),
+            about: Some("Compile the package\nMore details".to_string()),
+        },
+        CommandInfo::External {
+            name: "rebuild".to_string(),
+            path: PathBuf::from("/bin/nianjia-rebuild"),
+        },
+    ];
+
+    let mut out = Vec::new();
+    write_command_list(&mut out, commands.clone().into_iter(), false, false, None).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "Installed Commands:\n    build   Compile the package\n    rebuild\n"
+    );
+
+    let names = |filter| {
+        filter_commands(commands.iter().cloned().collect(), filter)
+            .map(|c| c.name())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names(None), ["build", "rebuild"]);
+    assert_eq!(names(Some("reb")), ["rebuild"]);
+    assert!(names(Some("test")).is_empty());
+
+    let mut out = Vec::new();
+    write_command_list(&mut out, commands.into_iter(), true, false, None).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "[{\"about\":\"Compile the package\\nMore details\",\"kind\":\"builtin\",\"name\":\"build\"},\
+         {\"kind\":\"external\",\"name\":\"rebuild\",\"path\":\"/bin/nianjia-rebuild\"}]\n"
+    );
+}
+
+#[test]
+fn user_aliases() {
+    use crate::core::shell::Shell;
+    use crate::util::flock::Filesystem;
+
+    let root = crate::util::config::test_root("user_aliases");
+    fs::write(
+        root.join("cwd").join(".nianjia").join("config"),
+        "[alias]\n\
+         rel = \"run --release\"\n\
+         dbg = [\"run\", \"--features\", \"a b\"]\n\
+         json = [\"build\", \"--message-format=json\", \"--features=a b\"]\n\
+         ping = \"pong -q\"\n\
+         pong = \"ping\"\n",
+    )
+    .unwrap();
+    let mut config = Config::new(
+        Shell::from_write(Box::new(io::sink())),
+        root.join("cwd"),
+        Filesystem::new(root.join("home")),
+    );
+    config.set_env(Default::default());
+
+    assert_eq!(aliased_command(&config, "rel").unwrap().unwrap(), ["run", "--release"]);
+    assert_eq!(
+        aliased_command(&config, "dbg").unwrap().unwrap(),
+        ["run", "--features", "a b"]
+    );
+    assert_eq!(
+        aliased_command(&config, "json").unwrap().unwrap(),
+        ["build", "--message-format=json", "--features=a b"]
+    );
+    assert!(aliased_command(&config, "none").unwrap().is_none());
+
+    let args = cli().get_matches_from_safe(["nianjia", "ping"]).unwrap();
+    let err = expand_aliases(&mut config, args, &mut Vec::new()).err().unwrap();
+    assert_eq!(
+        err.error.unwrap().to_string(),
+        "alias `ping` expands to itself: ping -> pong -> ping"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn list_external_commands() {
+    use std::collections::HashMap;
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::core::shell::Shell;
+    use crate::util::flock::Filesystem;
+
+    let root = crate::util::config::test_root("list_external_commands");
+    let first = root.join("first");
+    let second = root.join("second");
+    for (dir, name, mode) in &[
+        (&first, "nianjia-foo", 0o755),
+        (&first, "nianjia-data", 0o644),
+        (&first, "other", 0o755),
+        (&second, "nianjia-foo", 0o755),
+        (&second, "nianjia-bar", 0o755),
+    ] {
+        fs::create_dir_all(dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(*mode)).unwrap();
+    }
+
+    let mut config = Config::new(
+        Shell::from_write(Box::new(io::sink())),
+        root.join("cwd"),
+        Filesystem::new(root.join("home")),
+    );
+    let mut env = HashMap::new();
+    let path = env::join_paths([&first, &second]).unwrap();
+    env.insert("PATH".to_string(), path.into_string().unwrap());
+    config.set_env(env);
+
+    let commands = list_commands(&config).into_iter().collect::<Vec<_>>();
+    assert_eq!(
+        commands,
+        [
+            CommandInfo::External {
+                name: "bar".to_string(),
+                path: second.join("nianjia-bar"),
+            },
+            CommandInfo::External {
+                name: "foo".to_string(),
+                path: first.join("nianjia-foo"),
+            },
+        ]
+    );
+}
+
+#[test]
+fn closest_command() {
+    let names = || vec!["build".to_string(), "bench".to_string(), "run".to_string()].into_iter();
+    assert_eq!(closest("buld", names()).as_deref(), Some("build"));
+    assert_eq!(closest("rnu", names()).as_deref(), Some("run"));
+    assert_eq!(closest("publish", names()), None);
+}
@@ -1,10 +1,17 @@
+use std::collections::HashSet;
+use std::env;
 use std::fmt;
+use std::io::{self, BufRead};
+use std::str::FromStr;
 use std::io::prelude::Write;
+use std::fs::File;
+use std::mem;
+use std::path::Path;
 
-use termcolor::{ColorSpec, StandardStream, WriteColor};
-use termcolor::Color::{self, Red, Yellow};
+use termcolor::{ColorSpec, NoColor, StandardStream, WriteColor};
+use termcolor::Color::{self, Cyan, Green, Red, Yellow};
 
-use crate::util::errors::NianjiaResult;
+use crate::util::errors::{NianjiaResult, NianjiaResultExt};
 
 /// An abstraction around a `Write`able object that remembers preferences for output verbosity and
 /// color.
@@ -17,19 +24,43 @@ pub struct Shell {
     /// Flag that indicates the current line needs to be cleared before
     /// printing. Used when a progress bar is currently displayed.
     needs_clear: bool,
+    /// Answer `yes` to every confirmation prompt without asking (`--yes`).
+    assume_yes: bool,
+    /// Messages already printed by `warn_once`.
+    warned: HashSet<String>,
+    /// Whether to suppress a line identical to the previous one.
+    coalesce_repeats: bool,
+    /// The last line printed, when coalescing repeats.
+    last_line: Option<String>,
+    /// How many times `last_line` was suppressed.
+    repeats: usize,
+    /// Whether errors are reported as text or as JSON.
+    message_format: MessageFormat,
+    /// Warnings held back to be summarized, see `start_warning_summary`.
+    summarized: Option<(String, Vec<String>)>,
+    /// Whether a quiet shell prints how many warnings and errors there were,
+    /// see `print_quiet_summary`.
+    quiet_summary: bool,
+    /// How many warnings were reported, printed or not.
+    warnings: usize,
+    /// How many errors were reported.
+    errors: usize,
+    /// The length in bytes beyond which errors and warnings are cut short
+    /// unless verbose, see `set_max_message_len`.
+    max_message_len: Option<usize>,
 }
 
 impl fmt::Debug for Shell {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self.err {
-            ShellOut::Write(_) => f
+        match *self.err.primary() {
+            ShellOut::Stream { color_choice, .. } => f
                 .debug_struct("Shell")
                 .field("verbosity", &self.verbosity)
+                .field("color_choice", &color_choice)
                 .finish(),
-            ShellOut::Stream { color_choice, .. } => f
+            _ => f
                 .debug_struct("Shell")
                 .field("verbosity", &self.verbosity)
-                .field("color_choice", &color_choice)
                 .finish(),
         }
     }
@@ -43,15 +74,64 @@ pub enum Verbosity {
     Quiet,
 }
 
+impl FromStr for Verbosity {
+    type Err = failure::Error;
+
+    /// Parses `quiet`, `normal` or `verbose`, as in `term.verbosity`. `trace`
+    /// is also accepted as `verbose`; `Config::configure` additionally
+    /// enables the extra output of `-vv` for it.
+    fn from_str(s: &str) -> NianjiaResult<Verbosity> {
+        match s {
+            "quiet" => Ok(Verbosity::Quiet),
+            "normal" => Ok(Verbosity::Normal),
+            "verbose" | "trace" => Ok(Verbosity::Verbose),
+            _ => failure::bail!(
+                "verbosity must be quiet, normal, verbose or trace, but found `{}`",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Verbosity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Verbosity::Quiet => "quiet",
+            Verbosity::Normal => "normal",
+            Verbosity::Verbose => "verbose",
+        })
+    }
+}
+
+/// The format of the diagnostics nianjia reports.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageFormat {
+    Human,
+    /// One JSON object per line on stdout, such as
+    /// `{"reason":"diagnostic","level":"warning","message":"..."}`.
+    Json,
+}
+
 /// A `Write`able object, either with or without color support
 enum ShellOut {
-    /// A plain write object without color support
-    Write(Box<dyn Write>),
+    /// A plain write object without color support, which may stand in for a
+    /// terminal
+    Write { out: Box<dyn Write>, tty: bool },
     /// Color-enabled stdio, with information on whether color should be used
     Stream {
+        /// stderr, where messages go
         stream: StandardStream,
         tty: bool,
+        /// stdout, colored by the same choice but checked for a tty separately
+        stdout: StandardStream,
         color_choice: ColorChoice,
+        /// the environment variables steering `NianjiaAuto`
+        color_env: ColorEnv,
+    },
+    /// Another output whose stderr is copied, without color, to a log file
+    Tee {
+        primary: Box<ShellOut>,
+        secondary: Box<dyn Write>,
     },
 }
 
@@ -66,18 +146,137 @@ pub enum ColorChoice {
     NianjiaAuto,
 }
 
+/// The environment variables that steer `ColorChoice::NianjiaAuto`, taken
+/// from the process or from `Config` so they can be set in tests.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct ColorEnv {
+    /// `NO_COLOR` is set, to any value: never color.
+    pub no_color: bool,
+    /// `CLICOLOR_FORCE` is set to something other than `0`: always color.
+    pub clicolor_force: bool,
+    /// `CI` is set.
+    pub ci: bool,
+    /// `FORCE_COLOR` is set to something other than `0`, which colors
+    /// output in CI even without a tty.
+    pub force_color: bool,
+}
+
+impl ColorEnv {
+    /// Reads the variables through `lookup`, such as `Config::env_var`.
+    pub fn from_lookup<F>(lookup: F) -> ColorEnv
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let enabled = |key: &str| lookup(key).is_some_and(|v| !v.is_empty() && v != "0");
+        ColorEnv {
+            no_color: lookup("NO_COLOR").is_some(),
+            clicolor_force: enabled("CLICOLOR_FORCE"),
+            ci: lookup("CI").is_some(),
+            force_color: enabled("FORCE_COLOR"),
+        }
+    }
+
+    /// Reads the variables from the process environment.
+    pub fn from_process() -> ColorEnv {
+        ColorEnv::from_lookup(|key| env::var(key).ok())
+    }
+}
+
 impl Shell {
     /// Creates a new shell (color choice and verbosity), defaulting to 'auto' color and verbose
     /// output.
     pub fn new() -> Shell {
+        let color_env = ColorEnv::from_process();
         Shell {
             err: ShellOut::Stream {
-                stream: StandardStream::stderr(ColorChoice::NianjiaAuto.to_termcolor_color_choice()),
+                stream: StandardStream::stderr(
+                    ColorChoice::NianjiaAuto
+                        .to_termcolor_color_choice(atty::Stream::Stderr, &color_env),
+                ),
+                stdout: StandardStream::stdout(
+                    ColorChoice::NianjiaAuto
+                        .to_termcolor_color_choice(atty::Stream::Stdout, &color_env),
+                ),
                 color_choice: ColorChoice::NianjiaAuto,
+                color_env,
                 tty: atty::is(atty::Stream::Stderr),
             },
             verbosity: Verbosity::Verbose,
             needs_clear: false,
+            assume_yes: false,
+            warned: HashSet::new(),
+            coalesce_repeats: false,
+            last_line: None,
+            repeats: 0,
+            message_format: MessageFormat::Human,
+            summarized: None,
+            quiet_summary: false,
+            warnings: 0,
+            errors: 0,
+            max_message_len: None,
+        }
+    }
+
+    /// Creates a shell from a plain writable object, with no color, and max verbosity.
+    ///
+    /// # Examples
+    ///
+    /// Capturing what is printed, by keeping a handle on the buffer:
+    ///
+    /// ```
+    /// use std::cell::RefCell;
+    /// use std::io::{self, Write};
+    /// use std::rc::Rc;
+    ///
+    /// use nianjia::core::shell::Shell;
+    ///
+    /// #[derive(Clone, Default)]
+    /// struct Buffer(Rc<RefCell<Vec<u8>>>);
+    ///
+    /// impl Write for Buffer {
+    ///     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    ///         self.0.borrow_mut().write(buf)
+    ///     }
+    ///
+    ///     fn flush(&mut self) -> io::Result<()> {
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// let buffer = Buffer::default();
+    /// let mut shell = Shell::from_write(Box::new(buffer.clone()));
+    /// shell.warn("unused manifest key: foo").unwrap();
+    /// assert_eq!(
+    ///     &buffer.0.borrow()[..],
+    ///     &b"warning: unused manifest key: foo\n"[..]
+    /// );
+    /// ```
+    pub fn from_write(out: Box<dyn Write>) -> Shell {
+        Shell::with_write(out, false)
+    }
+
+    /// Like `from_write`, but treats the writer as a terminal, so that
+    /// progress is drawn in place as it would be on stderr.
+    pub fn from_write_tty(out: Box<dyn Write>) -> Shell {
+        Shell::with_write(out, true)
+    }
+
+    fn with_write(out: Box<dyn Write>, tty: bool) -> Shell {
+        Shell {
+            err: ShellOut::Write { out, tty },
+            verbosity: Verbosity::Verbose,
+            needs_clear: false,
+            assume_yes: false,
+            warned: HashSet::new(),
+            coalesce_repeats: false,
+            last_line: None,
+            repeats: 0,
+            message_format: MessageFormat::Human,
+            summarized: None,
+            quiet_summary: false,
+            warnings: 0,
+            errors: 0,
+            max_message_len: None,
         }
     }
 
@@ -96,30 +295,248 @@ impl Shell {
                 if self.needs_clear {
                     self.err_erase_line();
                 }
+                if self.coalesce_repeats {
+                    let line = match message {
+                        Some(message) => format!("{} {}", status, message),
+                        None => status.to_string(),
+                    };
+                    if self.last_line.as_ref() == Some(&line) {
+                        self.repeats += 1;
+                        return Ok(());
+                    }
+                    self.flush_repeats()?;
+                    self.last_line = Some(line);
+                }
                 self.err.print(status, message, color, justified)
             }
         }
     }
-        
+
+    /// Prints how often the last line was repeated, if it was suppressed.
+    /// Called once nianjia is about to exit, so a final streak isn't lost.
+    pub fn flush_repeats(&mut self) -> NianjiaResult<()> {
+        if self.repeats > 0 {
+            let count = format!("(x{})", self.repeats + 1);
+            writeln!(self.err.as_write(), "{:>12}", count)?;
+            self.repeats = 0;
+        }
+        Ok(())
+    }
+
+    /// Prints a fatal error rendered by `CliError::to_json`, counting it like
+    /// `error` does.
+    pub fn error_json(&mut self, value: serde_json::Value) -> NianjiaResult<()> {
+        self.flush_repeats()?;
+        self.errors += 1;
+        self.print_json(value)
+    }
+
+    /// Writes `value` as a line of JSON on stdout.
+    fn print_json(&mut self, value: serde_json::Value) -> NianjiaResult<()> {
+        if self.needs_clear {
+            self.err_erase_line();
+        }
+        writeln!(self.err.as_write_out(), "{}", value)?;
+        Ok(())
+    }
+
+    /// Copies everything printed to stderr from now on to the file at `path`,
+    /// without colors, replacing any earlier copy. Verbosity applies to both,
+    /// but only the terminal has its lines cleared.
+    pub fn tee_to(&mut self, path: &Path) -> NianjiaResult<()> {
+        let file = File::create(path)
+            .chain_err(|| format!("failed to create log file `{}`", path.display()))?;
+        let secondary = Box::new(file);
+        match self.err {
+            ShellOut::Tee {
+                secondary: ref mut old,
+                ..
+            } => *old = secondary,
+            _ => {
+                let placeholder = ShellOut::Write {
+                    out: Box::new(io::sink()),
+                    tty: false,
+                };
+                let primary = mem::replace(&mut self.err, placeholder);
+                self.err = ShellOut::Tee {
+                    primary: Box::new(primary),
+                    secondary,
+                };
+            }
+        }
+        Ok(())
+    }
+
     /// Gets a reference to the underlying writer.
     pub fn err(&mut self) -> &mut dyn Write {
         if self.needs_clear {
             self.err_erase_line();
         }
+        drop(self.flush_repeats());
+        self.last_line = None;
         self.err.as_write()
     }
 
+    /// Prints `message` on stderr without a newline and flushes it right
+    /// away, so that a prompt is seen before input is read.
+    pub fn print_now<T: fmt::Display>(&mut self, message: T) -> NianjiaResult<()> {
+        let err = self.err();
+        write!(err, "{}", message)?;
+        err.flush()?;
+        Ok(())
+    }
+
+    /// Gets a reference to stdout, for the program's output as opposed to
+    /// its diagnostics.
+    pub fn out(&mut self) -> &mut dyn Write {
+        if self.needs_clear {
+            self.err_erase_line();
+        }
+        self.err.as_write_out()
+    }
+
+    /// Prints a green status line like `status`, but on stdout, for tools
+    /// whose progress is their output. Nothing is printed when quiet.
+    pub fn out_status<T, U>(&mut self, status: T, message: U) -> NianjiaResult<()>
+    where
+        T: fmt::Display,
+        U: fmt::Display,
+    {
+        if self.verbosity == Verbosity::Quiet {
+            return Ok(());
+        }
+        if self.needs_clear {
+            self.err_erase_line();
+        }
+        self.err.print_out(&status, Some(&message), Green, true)
+    }
+
+    /// Writes `text` styled with `spec` when the stream supports color, or as
+    /// plain text otherwise.
+    pub fn write_styled(&mut self, spec: &ColorSpec, text: &str) -> NianjiaResult<()> {
+        if self.needs_clear {
+            self.err_erase_line();
+        }
+        self.flush_repeats()?;
+        self.last_line = None;
+        self.err.write_styled(spec, text)
+    }
+
+    /// Prints a green status line for step `current` of `total`, prefixed
+    /// with a dimmed `[current/total]` counter. The counter is padded to the
+    /// width of `total`, so the lines of all steps line up.
+    pub fn step<T, U>(
+        &mut self,
+        current: usize,
+        total: usize,
+        status: T,
+        message: U,
+    ) -> NianjiaResult<()>
+    where
+        T: fmt::Display,
+        U: fmt::Display,
+    {
+        if self.verbosity == Verbosity::Quiet {
+            return Ok(());
+        }
+        let width = total.to_string().len();
+        let counter = format!("[{:>width$}/{}]", current, total, width = width);
+        self.write_styled(ColorSpec::new().set_dimmed(true), &counter)?;
+        self.err.print(&status, Some(&message), Green, true)
+    }
+
+    /// Suppresses messages identical to the one printed just before, instead
+    /// printing a `(xN)` count once a different message comes along.
+    pub fn set_coalesce_repeats(&mut self, coalesce_repeats: bool) {
+        self.coalesce_repeats = coalesce_repeats;
+    }
+
+    /// Sets whether the next print should clear the current line.
+    pub fn set_needs_clear(&mut self, needs_clear: bool) {
+        self.needs_clear = needs_clear;
+    }
+
+    /// Returns `true` if stderr is a tty.
+    pub fn is_err_tty(&self) -> bool {
+        match *self.err.primary() {
+            ShellOut::Stream { tty, .. } | ShellOut::Write { tty, .. } => tty,
+            ShellOut::Tee { .. } => false,
+        }
+    }
+
+    /// Whether progress bars and spinners may be drawn in place right now:
+    /// stderr is a terminal and the shell isn't quiet. Checked on every
+    /// redraw, since the shell can be replaced after drawing started.
+    pub fn supports_status_drawing(&self) -> bool {
+        self.verbosity != Verbosity::Quiet && self.is_err_tty()
+    }
+
+    /// Gets the width of the terminal stderr is attached to. A plain write
+    /// object has no width. See `Config::term_width`, which also consults
+    /// `COLUMNS`.
+    pub fn err_width(&self) -> Option<usize> {
+        match *self.err.primary() {
+            ShellOut::Stream { .. } => imp::stderr_width(),
+            _ => None,
+        }
+    }
+
     /// Erase from cursor to end of line.
     pub fn err_erase_line(&mut self) {
-        if let ShellOut::Stream { tty: true, .. } = self.err {
+        if self.is_err_tty() {
             imp::err_erase_line(self);
             self.needs_clear = false;
         }
     }
 
-    /// Prints a red 'error' message.
+    /// Prints a green status, right-justified to 12 characters, followed by
+    /// `message`, like `   Compiling foo v0.1.0`.
+    pub fn status<T, U>(&mut self, status: T, message: U) -> NianjiaResult<()>
+    where
+        T: fmt::Display,
+        U: fmt::Display,
+    {
+        self.status_with_color(status, message, Green)
+    }
+
+    /// Like `status`, but with the status in `color`.
+    pub fn status_with_color<T, U>(
+        &mut self,
+        status: T,
+        message: U,
+        color: Color,
+    ) -> NianjiaResult<()>
+    where
+        T: fmt::Display,
+        U: fmt::Display,
+    {
+        if self.message_format == MessageFormat::Json {
+            if self.verbosity == Verbosity::Quiet {
+                return Ok(());
+            }
+            return self.print_json(serde_json::json!({
+                "reason": "diagnostic",
+                "level": "status",
+                "status": status.to_string(),
+                "message": message.to_string(),
+            }));
+        }
+        self.print(&status, Some(&message), color, true)
+    }
+
+    /// Prints a red 'error' message, even when quiet.
     pub fn error<T: fmt::Display>(&mut self, message: T) -> NianjiaResult<()> {
-        self.print(&"error:", Some(&message), Red, false)
+        if self.needs_clear {
+            self.err_erase_line();
+        }
+        self.flush_repeats()?;
+        self.last_line = None;
+        self.errors += 1;
+        let message = self.limit_message(&message);
+        if self.message_format == MessageFormat::Json {
+            return self.print_json(diagnostic("error", &message, &[]));
+        }
+        self.err.print(&"error:", Some(&message), Red, false)
     }
     
     /// Gets the verbosity of the shell.
@@ -127,51 +544,240 @@ impl Shell {
         self.verbosity
     }
     
+    /// Prints a cyan 'note' message.
+    pub fn note<T: fmt::Display>(&mut self, message: T) -> NianjiaResult<()> {
+        self.print(&"note:", Some(&message), Cyan, false)
+    }
+
     /// Prints an amber 'warning' message.
     pub fn warn<T: fmt::Display>(&mut self, message: T) -> NianjiaResult<()> {
+        self.warnings += 1;
+        let message = self.limit_message(&message);
+        self.emit_warning(message)
+    }
+
+    /// Cuts `message` short to `max_message_len` bytes, unless verbose.
+    fn limit_message(&self, message: &dyn fmt::Display) -> String {
+        let message = message.to_string();
+        let max = match self.max_message_len {
+            Some(max) if message.len() > max && self.verbosity != Verbosity::Verbose => max,
+            _ => return message,
+        };
+        let mut end = max;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        format!(
+            "{}… (truncated, run with --verbose for full output)",
+            &message[..end]
+        )
+    }
+
+    /// Limits errors and warnings to `max` bytes unless verbose, so that
+    /// huge messages, like the output of a failed process, don't flood the
+    /// terminal. They are not limited by default.
+    pub fn set_max_message_len(&mut self, max: Option<usize>) {
+        self.max_message_len = max;
+    }
+
+    /// Prints a warning, or holds it back to be summarized, without counting
+    /// it.
+    fn emit_warning<T: fmt::Display>(&mut self, message: T) -> NianjiaResult<()> {
         match self.verbosity {
             Verbosity::Quiet => Ok(()),
+            Verbosity::Normal if self.summarized.is_some() => {
+                if let Some((_, ref mut warnings)) = self.summarized {
+                    warnings.push(message.to_string());
+                }
+                Ok(())
+            }
+            _ if self.message_format == MessageFormat::Json => {
+                self.print_json(diagnostic("warning", &message, &[]))
+            }
             _ => self.print(&"warning:", Some(&message), Yellow, false),
         }
     }
 
+    /// Holds back warnings until `finish_warning_summary`, which prints a
+    /// count of them labelled `what` instead. Verbose shells print every
+    /// warning as usual.
+    pub fn start_warning_summary(&mut self, what: &str) {
+        self.summarized = Some((what.to_string(), Vec::new()));
+    }
+
+    /// Prints the warnings held back since `start_warning_summary`: a single
+    /// warning as is, and more as a count.
+    pub fn finish_warning_summary(&mut self) -> NianjiaResult<()> {
+        let (what, mut warnings) = match self.summarized.take() {
+            Some(summarized) => summarized,
+            None => return Ok(()),
+        };
+        match warnings.len() {
+            0 => Ok(()),
+            1 => self.emit_warning(warnings.remove(0)),
+            n => self.emit_warning(format!("{} {}; run with --verbose to see all", n, what)),
+        }
+    }
+
+    /// Prints an amber 'warning' message, unless the same message was already
+    /// printed through this method.
+    pub fn warn_once<T: fmt::Display>(&mut self, message: T) -> NianjiaResult<()> {
+        let message = message.to_string();
+        if self.warned.contains(&message) {
+            return Ok(());
+        }
+        self.warn(&message)?;
+        self.warned.insert(message);
+        Ok(())
+    }
+
+    /// Makes a quiet shell print how many warnings and errors there were
+    /// when `print_quiet_summary` is called, as `term.quiet-summary` does.
+    pub fn set_quiet_summary(&mut self, quiet_summary: bool) {
+        self.quiet_summary = quiet_summary;
+    }
+
+    /// Prints `N warnings, M errors` if the shell is quiet, asked to do so
+    /// with `set_quiet_summary`, and there was at least one of either. Called
+    /// once nianjia is about to exit.
+    pub fn print_quiet_summary(&mut self) -> NianjiaResult<()> {
+        if !self.quiet_summary
+            || self.verbosity != Verbosity::Quiet
+            || self.warnings + self.errors == 0
+        {
+            return Ok(());
+        }
+        let plural = |n: usize, what: &str| {
+            format!("{} {}{}", n, what, if n == 1 { "" } else { "s" })
+        };
+        let warnings = plural(self.warnings, "warning");
+        let errors = plural(self.errors, "error");
+        writeln!(self.err(), "{}, {}", warnings, errors)?;
+        Ok(())
+    }
+
     /// Updates the verbosity of the shell.
     pub fn set_verbosity(&mut self, verbosity: Verbosity) {
         self.verbosity = verbosity;
     }
 
+    pub fn message_format(&self) -> MessageFormat {
+        self.message_format
+    }
 
-    /// Updates the color choice (always, never, or auto) from a string..
+    pub fn set_message_format(&mut self, message_format: MessageFormat) {
+        self.message_format = message_format;
+    }
+
+
+    /// Makes every confirmation prompt answer `yes` without asking.
+    pub fn set_assume_yes(&mut self, assume_yes: bool) {
+        self.assume_yes = assume_yes;
+    }
+
+    /// Asks a yes/no question on stderr and reads the answer from stdin.
+    ///
+    /// Returns `default` on an empty answer, or without asking when stdin is
+    /// not a tty so scripts never block. Returns `true` right away if
+    /// `--yes` was passed.
+    pub fn confirm(&mut self, prompt: &str, default: bool) -> NianjiaResult<bool> {
+        let tty = atty::is(atty::Stream::Stdin);
+        let stdin = io::stdin();
+        let mut input = stdin.lock();
+        self.confirm_from(prompt, default, &mut input, tty)
+    }
+
+    fn confirm_from(
+        &mut self,
+        prompt: &str,
+        default: bool,
+        input: &mut dyn BufRead,
+        tty: bool,
+    ) -> NianjiaResult<bool> {
+        if self.assume_yes {
+            return Ok(true);
+        }
+        if !tty {
+            return Ok(default);
+        }
+
+        let choices = if default { "[Y/n]" } else { "[y/N]" };
+        self.print_now(format_args!("{} {} ", prompt, choices))?;
+
+        let mut answer = String::new();
+        input.read_line(&mut answer)?;
+        match &answer.trim().to_lowercase()[..] {
+            "y" | "yes" => Ok(true),
+            "n" | "no" => Ok(false),
+            _ => Ok(default),
+        }
+    }
+
+    /// Gets the current color choice.
+    ///
+    /// If we are not using a color stream, this will always return `Never`, even if the color
+    /// choice has been set to something else.
+    pub fn color_choice(&self) -> ColorChoice {
+        match *self.err.primary() {
+            ShellOut::Stream { color_choice, .. } => color_choice,
+            _ => ColorChoice::Never,
+        }
+    }
+
+    /// Updates the color choice (always, never, or auto) from a string, for
+    /// both stderr and stdout.
     pub fn set_color_choice(&mut self, color: Option<&str>) -> NianjiaResult<()> {
+        let cfg = ColorChoice::from_arg(color)?;
+        self.update_color(|choice, _| *choice = cfg);
+        Ok(())
+    }
+
+    /// Replaces the environment variables consulted when the color choice is
+    /// auto, which are read from the process when the shell is created.
+    pub fn set_color_env(&mut self, env: ColorEnv) {
+        self.update_color(|_, color_env| *color_env = env);
+    }
+
+    /// Changes the color choice or environment and reopens both streams.
+    fn update_color(&mut self, update: impl FnOnce(&mut ColorChoice, &mut ColorEnv)) {
         if let ShellOut::Stream {
             ref mut stream,
+            ref mut stdout,
             ref mut color_choice,
+            ref mut color_env,
             ..
-        } = self.err
+        } = *self.err.primary_mut()
         {
-            let cfg = match color {
-                Some("always") => ColorChoice::Always,
-                Some("never") => ColorChoice::Never,
+            update(color_choice, color_env);
+            let cfg = *color_choice;
+            let err_choice = cfg.to_termcolor_color_choice(atty::Stream::Stderr, color_env);
+            let out_choice = cfg.to_termcolor_color_choice(atty::Stream::Stdout, color_env);
+            *stream = StandardStream::stderr(err_choice);
+            *stdout = StandardStream::stdout(out_choice);
+        }
+    }
 
-                Some("auto") | None => ColorChoice::NianjiaAuto,
+    /// Whether messages on stderr are colored.
+    pub fn err_supports_color(&self) -> bool {
+        match *self.err.primary() {
+            ShellOut::Stream { ref stream, .. } => stream.supports_color(),
+            _ => false,
+        }
+    }
 
-                Some(arg) => failure::bail!(
-                    "argument for --color must be auto, always, or \
-                     never, but found `{}`",
-                    arg
-                ),
-            };
-            *color_choice = cfg;
-            *stream = StandardStream::stderr(cfg.to_termcolor_color_choice());
+    /// Whether output on stdout is colored.
+    pub fn out_supports_color(&self) -> bool {
+        match *self.err.primary() {
+            ShellOut::Stream { ref stdout, .. } => stdout.supports_color(),
+            _ => false,
         }
-        Ok(())
     }
 }
 
 impl ShellOut {
-    /// Prints out a message with a status. The status comes first, and is bold plus the given
-    /// color. The status can be justified, in which case the max width that will right align is
-    /// 12 chars.
+    /// Prints out a message with a status on stderr. The status comes first, and is bold plus
+    /// the given color. The status can be justified, in which case the max width that will
+    /// right align is 12 chars.
     fn print(
         &mut self,
         status: &dyn fmt::Display,
@@ -181,69 +787,223 @@ impl ShellOut {
     ) -> NianjiaResult<()> {
         match *self {
             ShellOut::Stream { ref mut stream, .. } => {
-                stream.reset()?;
-                stream.set_color(ColorSpec::new().set_bold(true).set_fg(Some(color)))?;
-                if justified {
-                    write!(stream, "{:>12}", status)?;
-                } else {
-                    write!(stream, "{}", status)?;
-                }
-                stream.reset()?;
-                match message {
-                    Some(message) => writeln!(stream, " {}", message)?,
-                    None => write!(stream, " ")?,
-                }
+                print_status(stream, status, message, color, justified)
             }
-            ShellOut::Write(ref mut w) => {
-                if justified {
-                    write!(w, "{:>12}", status)?;
-                } else {
-                    write!(w, "{}", status)?;
-                }
-                match message {
-                    Some(message) => writeln!(w, " {}", message)?,
-                    None => write!(w, " ")?,
-                }
+            ShellOut::Write { out: ref mut w, .. } => {
+                print_status(&mut NoColor::new(w), status, message, color, justified)
+            }
+            ShellOut::Tee {
+                ref mut primary,
+                ref mut secondary,
+            } => {
+                primary.print(status, message, color, justified)?;
+                print_status(&mut NoColor::new(secondary), status, message, color, justified)
+            }
+        }
+    }
+
+    /// Like `print`, but on stdout.
+    fn print_out(
+        &mut self,
+        status: &dyn fmt::Display,
+        message: Option<&dyn fmt::Display>,
+        color: Color,
+        justified: bool,
+    ) -> NianjiaResult<()> {
+        match *self {
+            ShellOut::Stream { ref mut stdout, .. } => {
+                print_status(stdout, status, message, color, justified)
+            }
+            ShellOut::Write { out: ref mut w, .. } => {
+                print_status(&mut NoColor::new(w), status, message, color, justified)
+            }
+            ShellOut::Tee { ref mut primary, .. } => {
+                primary.print_out(status, message, color, justified)
+            }
+        }
+    }
+
+    /// Writes `text` styled with `spec` when the stream supports color, or as
+    /// plain text otherwise.
+    fn write_styled(&mut self, spec: &ColorSpec, text: &str) -> NianjiaResult<()> {
+        match *self {
+            ShellOut::Stream { ref mut stream, .. } => write_styled(stream, spec, text),
+            ShellOut::Write { out: ref mut w, .. } => {
+                write!(w, "{}", text)?;
+                Ok(())
+            }
+            ShellOut::Tee {
+                ref mut primary,
+                ref mut secondary,
+            } => {
+                primary.write_styled(spec, text)?;
+                write!(secondary, "{}", text)?;
+                Ok(())
             }
         }
-        Ok(())
     }
 
     /// Gets this object as a `io::Write`.
     fn as_write(&mut self) -> &mut dyn Write {
+        match self {
+            ShellOut::Stream { stream, .. } => stream,
+            ShellOut::Write { out: w, .. } => w,
+            tee @ ShellOut::Tee { .. } => tee,
+        }
+    }
+
+    /// Gets stdout as a `io::Write`. A plain write object serves as both.
+    fn as_write_out(&mut self) -> &mut dyn Write {
         match *self {
-            ShellOut::Stream { ref mut stream, .. } => stream,
-            ShellOut::Write(ref mut w) => w,
+            ShellOut::Stream { ref mut stdout, .. } => stdout,
+            ShellOut::Write { out: ref mut w, .. } => w,
+            ShellOut::Tee { ref mut primary, .. } => primary.as_write_out(),
+        }
+    }
+
+    /// Gets the terminal or writer that isn't a copy.
+    fn primary(&self) -> &ShellOut {
+        match *self {
+            ShellOut::Tee { ref primary, .. } => primary.primary(),
+            ref out => out,
+        }
+    }
+
+    fn primary_mut(&mut self) -> &mut ShellOut {
+        match self {
+            ShellOut::Tee { primary, .. } => primary.primary_mut(),
+            out => out,
         }
     }
 }
 
+/// Lets `as_write` hand out a `ShellOut::Tee` itself, copying each write to
+/// the log file.
+impl Write for ShellOut {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            ShellOut::Tee {
+                ref mut primary,
+                ref mut secondary,
+            } => {
+                let n = primary.as_write().write(buf)?;
+                secondary.write_all(&buf[..n])?;
+                Ok(n)
+            }
+            _ => self.as_write().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let ShellOut::Tee {
+            ref mut secondary, ..
+        } = *self
+        {
+            secondary.flush()?;
+        }
+        self.primary_mut().as_write().flush()
+    }
+}
+
+/// Writes a status line, see `ShellOut::print`.
+fn print_status(
+    out: &mut dyn WriteColor,
+    status: &dyn fmt::Display,
+    message: Option<&dyn fmt::Display>,
+    color: Color,
+    justified: bool,
+) -> NianjiaResult<()> {
+    out.reset()?;
+    out.set_color(ColorSpec::new().set_bold(true).set_fg(Some(color)))?;
+    if justified {
+        write!(out, "{:>12}", status)?;
+    } else {
+        write!(out, "{}", status)?;
+    }
+    out.reset()?;
+    match message {
+        Some(message) => writeln!(out, " {}", message)?,
+        None => write!(out, " ")?,
+    }
+    Ok(())
+}
+
 impl ColorChoice {
-    /// Converts our color choice to termcolor's version.
-    fn to_termcolor_color_choice(self) -> termcolor::ColorChoice {
+    /// Parses the value of `--color` or `term.color`, where `None` means auto.
+    pub fn from_arg(color: Option<&str>) -> NianjiaResult<ColorChoice> {
+        match color {
+            Some("always") => Ok(ColorChoice::Always),
+            Some("never") => Ok(ColorChoice::Never),
+
+            Some("auto") | None => Ok(ColorChoice::NianjiaAuto),
+
+            Some(arg) => failure::bail!(
+                "argument for --color must be auto, always, or \
+                 never, but found `{}`",
+                arg
+            ),
+        }
+    }
+
+    /// Converts our color choice to termcolor's version for `stream`, which
+    /// auto-detection checks for a tty.
+    fn to_termcolor_color_choice(
+        self,
+        stream: atty::Stream,
+        env: &ColorEnv,
+    ) -> termcolor::ColorChoice {
         match self {
             ColorChoice::Always => termcolor::ColorChoice::Always,
             ColorChoice::Never => termcolor::ColorChoice::Never,
-            ColorChoice::NianjiaAuto => {
-                if atty::is(atty::Stream::Stderr) {
-                    termcolor::ColorChoice::Auto
-                } else {
-                    termcolor::ColorChoice::Never
-                }
-            }
+            ColorChoice::NianjiaAuto => auto_color_choice(atty::is(stream), env),
         }
     }
 }
 
-#[cfg(any(target_os = "linux", target_os = "macos"))]
+/// Decides on color when it's `auto`. `NO_COLOR` turns it off and
+/// `CLICOLOR_FORCE` on; otherwise only a tty gets color, unless running in
+/// CI with `FORCE_COLOR` set, as some CIs render ANSI colors in their logs.
+fn auto_color_choice(tty: bool, env: &ColorEnv) -> termcolor::ColorChoice {
+    if env.no_color {
+        termcolor::ColorChoice::Never
+    } else if env.clicolor_force {
+        termcolor::ColorChoice::Always
+    } else if tty {
+        termcolor::ColorChoice::Auto
+    } else if env.ci && env.force_color {
+        termcolor::ColorChoice::Always
+    } else {
+        termcolor::ColorChoice::Never
+    }
+}
+
+#[cfg(unix)]
 mod imp {
+    use std::mem;
+
     use super::Shell;
     
+    /// Gets the width of the terminal stderr is attached to.
+    pub fn stderr_width() -> Option<usize> {
+        unsafe {
+            let mut winsize: libc::winsize = mem::zeroed();
+            // The `as _` is needed because the type of `TIOCGWINSZ` differs
+            // between platforms.
+            if libc::ioctl(libc::STDERR_FILENO, libc::TIOCGWINSZ as _, &mut winsize) < 0 {
+                None
+            } else if winsize.ws_col > 0 {
+                Some(winsize.ws_col as usize)
+            } else {
+                None
+            }
+        }
+    }
+
     pub fn err_erase_line(shell: &mut Shell) {
         // This is the "EL - Erase in Line" sequence. It clears from the cursor
         // to the end of line.
         // https://en.wikipedia.org/wiki/ANSI_escape_code#CSI_sequences
-        let _ = shell.err.as_write().write_all(b"\x1B[K");
+        let _ = shell.err.primary_mut().as_write().write_all(b"\x1B[K");
     }
 }
 
@@ -303,10 +1063,489 @@ mod imp {
     }
 }
 
-#[cfg(any(all(unix, not(any(target_os = "linux", target_os = "macos"))), windows,))]
+/// Renders a message at `level` for `MessageFormat::Json`, with the messages
+/// of its causes. Warnings and errors share this schema, see
+/// `CliError::to_json`.
+pub fn diagnostic(level: &str, message: &dyn fmt::Display, causes: &[String]) -> serde_json::Value {
+    serde_json::json!({
+        "reason": "diagnostic",
+        "level": level,
+        "message": message.to_string(),
+        "causes": causes,
+    })
+}
+
+#[cfg(windows)]
 fn default_err_erase_line(shell: &mut Shell) {
     if let Some(max_width) = imp::stderr_width() {
         let blank = " ".repeat(max_width);
-        drop(write!(shell.err.as_write(), "{}\r", blank));
+        drop(write!(shell.err.primary_mut().as_write(), "{}\r", blank));
+    }
+}
+
+/// Writes `rows` of names and summaries as two aligned columns, indented by
+/// four spaces.
+///
+/// The first column is as wide as the longest name, up to half of `width`.
+/// Summaries are cut short with `…` to keep lines within `width`, if given.
+pub fn print_table(
+    out: &mut dyn Write,
+    rows: &[(String, String)],
+    width: Option<usize>,
+) -> io::Result<()> {
+    let longest = rows.iter().map(|(name, _)| name.chars().count()).max().unwrap_or(0);
+    let column = match width {
+        Some(width) => longest.min(width.saturating_sub(4) / 2),
+        None => longest,
+    };
+    for (name, summary) in rows {
+        if summary.is_empty() {
+            writeln!(out, "    {}", name)?;
+            continue;
+        }
+        let prefix = format!("    {:<width$} ", name, width = column);
+        match width {
+            Some(width) => {
+                let room = width.saturating_sub(prefix.chars().count());
+                writeln!(out, "{}{}", prefix, truncate(summary, room))?
+            }
+            None => writeln!(out, "{}{}", prefix, summary)?,
+        }
+    }
+    Ok(())
+}
+
+/// Shortens `s` to at most `max` characters, ending it with `…` if cut.
+fn truncate(s: &str, max: usize) -> String {
+    if s.chars().count() <= max {
+        return s.to_string();
+    }
+    let mut cut = s.chars().take(max.saturating_sub(1)).collect::<String>();
+    if max > 0 {
+        cut.push('…');
     }
+    cut
+}
+
+/// Writes `text` with `spec` applied, resetting the style afterwards.
+fn write_styled(out: &mut dyn WriteColor, spec: &ColorSpec, text: &str) -> NianjiaResult<()> {
+    out.reset()?;
+    out.set_color(spec)?;
+    write!(out, "{}", text)?;
+    out.reset()?;
+    Ok(())
+}
+
+/// A writer whose contents can still be read after it is handed to
+/// `Shell::from_write`, for capturing output in tests.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct Capture(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+#[cfg(test)]
+impl Capture {
+    pub(crate) fn shell() -> (Shell, Capture) {
+        let capture = Capture::default();
+        (Shell::from_write(Box::new(capture.clone())), capture)
+    }
+
+    /// Like `shell`, but the shell claims stderr is a terminal.
+    pub(crate) fn tty_shell() -> (Shell, Capture) {
+        let capture = Capture::default();
+        (Shell::from_write_tty(Box::new(capture.clone())), capture)
+    }
+
+    pub(crate) fn contents(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn from_write_captures() {
+    let (mut shell, capture) = Capture::shell();
+    assert_eq!(shell.verbosity(), Verbosity::Verbose);
+    assert!(!shell.is_err_tty());
+    shell.warn("unused manifest key: foo").unwrap();
+    assert_eq!(capture.contents(), "warning: unused manifest key: foo\n");
+}
+
+#[test]
+fn confirm_answers() {
+    let (mut shell, capture) = Capture::shell();
+    let mut ask = |answer: &str, default| {
+        shell
+            .confirm_from("Are you sure?", default, &mut answer.as_bytes(), true)
+            .unwrap()
+    };
+    assert!(ask("y\n", false));
+    assert!(ask("YES\n", false));
+    assert!(!ask("n\n", true));
+    assert!(ask("\n", true));
+    assert!(!ask("", false));
+    assert_eq!(
+        capture.contents(),
+        "Are you sure? [y/N] Are you sure? [y/N] Are you sure? [Y/n] \
+         Are you sure? [Y/n] Are you sure? [y/N] "
+    );
+}
+
+#[test]
+fn prompt_flushed_before_reading() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Log(Rc<RefCell<Vec<&'static str>>>);
+
+    impl Write for Log {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().push("write");
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.borrow_mut().push("flush");
+            Ok(())
+        }
+    }
+
+    impl io::Read for Log {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.borrow_mut().push("read");
+            io::Read::read(&mut &b"y\n"[..], buf)
+        }
+    }
+
+    let log = Log::default();
+    let mut shell = Shell::from_write(Box::new(log.clone()));
+    let mut input = io::BufReader::new(log.clone());
+    assert!(shell.confirm_from("Delete?", false, &mut input, true).unwrap());
+    let mut events = log.0.borrow().clone();
+    events.dedup();
+    assert_eq!(events, ["write", "flush", "read"]);
+}
+
+#[test]
+fn confirm_non_interactive() {
+    let mut shell = Shell::new();
+    assert!(!shell.confirm_from("Delete?", false, &mut &b"y\n"[..], false).unwrap());
+    assert!(shell.confirm_from("Delete?", true, &mut &b"n\n"[..], false).unwrap());
+
+    shell.set_assume_yes(true);
+    assert!(shell.confirm_from("Delete?", false, &mut &b"n\n"[..], true).unwrap());
+}
+
+#[test]
+fn coalesce_repeated_lines() {
+    let (mut shell, capture) = Capture::shell();
+    shell.set_coalesce_repeats(true);
+    for _ in 0..3 {
+        shell.warn("waiting on lock").unwrap();
+    }
+    shell.warn("lock acquired").unwrap();
+    shell.warn("lock acquired").unwrap();
+    writeln!(shell.err(), "done").unwrap();
+    assert_eq!(
+        capture.contents(),
+        "warning: waiting on lock\n        (x3)\nwarning: lock acquired\n        (x2)\ndone\n"
+    );
+
+    let (mut shell, capture) = Capture::shell();
+    shell.set_coalesce_repeats(true);
+    shell.warn("waiting on lock").unwrap();
+    shell.warn("waiting on lock").unwrap();
+    shell.flush_repeats().unwrap();
+    shell.flush_repeats().unwrap();
+    assert_eq!(capture.contents(), "warning: waiting on lock\n        (x2)\n");
+}
+
+#[test]
+fn write_styled_resets() {
+    let mut spec = ColorSpec::new();
+    spec.set_bold(true);
+
+    let mut out = termcolor::Ansi::new(Vec::new());
+    write_styled(&mut out, &spec, "hello").unwrap();
+    assert_eq!(out.into_inner(), b"\x1b[0m\x1b[0m\x1b[1mhello\x1b[0m");
+
+    let mut out = termcolor::NoColor::new(Vec::new());
+    write_styled(&mut out, &spec, "hello").unwrap();
+    assert_eq!(out.into_inner(), b"hello");
+
+    let (mut shell, capture) = Capture::shell();
+    shell.write_styled(&spec, "plain").unwrap();
+    assert_eq!(capture.contents(), "plain");
+}
+
+#[test]
+fn summarize_warnings() {
+    let (mut shell, capture) = Capture::shell();
+    shell.set_verbosity(Verbosity::Normal);
+    shell.start_warning_summary("configuration warnings");
+    shell.warn("unused key `a`").unwrap();
+    shell.warn("unused key `b`").unwrap();
+    shell.finish_warning_summary().unwrap();
+    assert_eq!(
+        capture.contents(),
+        "warning: 2 configuration warnings; run with --verbose to see all\n"
+    );
+
+    let (mut shell, capture) = Capture::shell();
+    shell.start_warning_summary("configuration warnings");
+    shell.warn("unused key `a`").unwrap();
+    shell.warn("unused key `b`").unwrap();
+    shell.finish_warning_summary().unwrap();
+    assert_eq!(
+        capture.contents(),
+        "warning: unused key `a`\nwarning: unused key `b`\n"
+    );
+}
+
+#[test]
+fn color_choice_applies_to_both_streams() {
+    let mut shell = Shell::new();
+    shell.set_color_choice(Some("always")).unwrap();
+    assert!(shell.err_supports_color());
+    assert!(shell.out_supports_color());
+
+    shell.set_color_choice(Some("never")).unwrap();
+    assert!(!shell.err_supports_color());
+    assert!(!shell.out_supports_color());
+}
+
+#[test]
+fn print_table_fits_width() {
+    let rows = vec![
+        ("b".to_string(), "Short".to_string()),
+        ("a-very-long-command".to_string(), "Does something rather elaborate".to_string()),
+        ("ext".to_string(), String::new()),
+    ];
+    let mut out = Vec::new();
+    print_table(&mut out, &rows, Some(50)).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "    b                   Short\n\
+         \x20   a-very-long-command Does something rather ela…\n\
+         \x20   ext\n"
+    );
+}
+
+#[test]
+fn verbosity_strings() {
+    assert_eq!("quiet".parse::<Verbosity>().unwrap(), Verbosity::Quiet);
+    assert_eq!("normal".parse::<Verbosity>().unwrap(), Verbosity::Normal);
+    assert_eq!("verbose".parse::<Verbosity>().unwrap(), Verbosity::Verbose);
+    assert_eq!("trace".parse::<Verbosity>().unwrap(), Verbosity::Verbose);
+    assert!("loud".parse::<Verbosity>().is_err());
+    for &v in &[Verbosity::Quiet, Verbosity::Normal, Verbosity::Verbose] {
+        assert_eq!(v.to_string().parse::<Verbosity>().unwrap(), v);
+    }
+}
+
+#[test]
+fn step_counters_align() {
+    let (mut shell, capture) = Capture::shell();
+    shell.step(1, 10, "Fetching", "sources").unwrap();
+    shell.step(10, 10, "Finished", "in 2s").unwrap();
+    assert_eq!(
+        capture.contents(),
+        "[ 1/10]    Fetching sources\n\
+         [10/10]    Finished in 2s\n"
+    );
+
+    shell.set_verbosity(Verbosity::Quiet);
+    shell.step(2, 10, "Building", "nothing").unwrap();
+    assert!(!capture.contents().contains("nothing"));
+}
+
+#[test]
+fn ci_force_color() {
+    use termcolor::ColorChoice::{Always, Auto, Never};
+
+    let ci = |force_color| ColorEnv {
+        ci: true,
+        force_color,
+        ..ColorEnv::default()
+    };
+    assert_eq!(auto_color_choice(false, &ci(true)), Always);
+    assert_eq!(auto_color_choice(false, &ci(false)), Never);
+    assert_eq!(
+        auto_color_choice(false, &ColorEnv { force_color: true, ..ColorEnv::default() }),
+        Never
+    );
+    assert_eq!(auto_color_choice(true, &ci(false)), Auto);
+}
+
+#[test]
+fn status_lines() {
+    let (mut shell, capture) = Capture::shell();
+    shell.status("Compiling", "foo v0.1.0").unwrap();
+    shell.status_with_color("Removed", "3 files", Color::Cyan).unwrap();
+    shell.set_verbosity(Verbosity::Quiet);
+    shell.status("Finished", "dev").unwrap();
+    assert_eq!(
+        capture.contents(),
+        "   Compiling foo v0.1.0\n     Removed 3 files\n"
+    );
+}
+
+#[test]
+fn quiet_stdout_status() {
+    let (mut shell, capture) = Capture::shell();
+    shell.out_status("Packaged", "3 files").unwrap();
+    writeln!(shell.out(), "foo-0.1.0.crate").unwrap();
+    shell.set_verbosity(Verbosity::Quiet);
+    shell.out_status("Packaged", "4 files").unwrap();
+    writeln!(shell.out(), "foo-0.2.0.crate").unwrap();
+    assert_eq!(
+        capture.contents(),
+        "    Packaged 3 files\nfoo-0.1.0.crate\nfoo-0.2.0.crate\n"
+    );
+}
+
+#[test]
+fn quiet_keeps_errors() {
+    let (mut shell, capture) = Capture::shell();
+    shell.set_verbosity(Verbosity::Quiet);
+    shell.warn("unused key").unwrap();
+    shell.status("Compiling", "foo").unwrap();
+    shell.error("could not compile `foo`").unwrap();
+    assert_eq!(capture.contents(), "error: could not compile `foo`\n");
+}
+
+#[test]
+fn no_color_and_clicolor_force() {
+    use std::collections::HashMap;
+    use termcolor::ColorChoice::{Always, Auto, Never};
+
+    let env = |vars: &[(&str, &str)]| {
+        let vars: HashMap<_, _> = vars.iter().cloned().collect();
+        ColorEnv::from_lookup(|key| vars.get(key).map(|v| v.to_string()))
+    };
+    assert_eq!(auto_color_choice(true, &env(&[])), Auto);
+    assert_eq!(auto_color_choice(true, &env(&[("NO_COLOR", "")])), Never);
+    assert_eq!(auto_color_choice(false, &env(&[("CLICOLOR_FORCE", "1")])), Always);
+    assert_eq!(auto_color_choice(false, &env(&[("CLICOLOR_FORCE", "0")])), Never);
+    assert_eq!(
+        auto_color_choice(false, &env(&[("NO_COLOR", "1"), ("CLICOLOR_FORCE", "1")])),
+        Never
+    );
+
+    // Explicit choices ignore the environment.
+    let forced = env(&[("CLICOLOR_FORCE", "1")]);
+    let plain = env(&[("NO_COLOR", "1")]);
+    assert_eq!(ColorChoice::Never.to_termcolor_color_choice(atty::Stream::Stderr, &forced), Never);
+    assert_eq!(ColorChoice::Always.to_termcolor_color_choice(atty::Stream::Stderr, &plain), Always);
+}
+
+#[test]
+fn plain_writer_has_no_width() {
+    assert_eq!(Shell::from_write(Box::new(io::sink())).err_width(), None);
+}
+
+#[test]
+fn quiet_summary_counts() {
+    let (mut shell, capture) = Capture::shell();
+    shell.set_verbosity(Verbosity::Quiet);
+    shell.set_quiet_summary(true);
+    shell.warn("unused key `a`").unwrap();
+    shell.warn_once("unused key `b`").unwrap();
+    shell.warn_once("unused key `b`").unwrap();
+    shell.error("could not compile `foo`").unwrap();
+    shell.print_quiet_summary().unwrap();
+    assert_eq!(
+        capture.contents(),
+        "error: could not compile `foo`\n2 warnings, 1 error\n"
+    );
+
+    let (mut shell, capture) = Capture::shell();
+    shell.set_quiet_summary(true);
+    shell.warn("unused key `a`").unwrap();
+    shell.print_quiet_summary().unwrap();
+    assert_eq!(capture.contents(), "warning: unused key `a`\n");
+}
+
+#[test]
+fn json_diagnostics() {
+    let (mut shell, capture) = Capture::shell();
+    shell.set_message_format(MessageFormat::Json);
+    shell.warn("unused key `a`").unwrap();
+    shell.error("could not compile `foo`").unwrap();
+    shell.status("Compiling", "foo v0.1.0").unwrap();
+    let lines = capture
+        .contents()
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        lines,
+        [
+            serde_json::json!({
+                "reason": "diagnostic",
+                "level": "warning",
+                "message": "unused key `a`",
+                "causes": [],
+            }),
+            serde_json::json!({
+                "reason": "diagnostic",
+                "level": "error",
+                "message": "could not compile `foo`",
+                "causes": [],
+            }),
+            serde_json::json!({
+                "reason": "diagnostic",
+                "level": "status",
+                "status": "Compiling",
+                "message": "foo v0.1.0",
+            }),
+        ]
+    );
+}
+
+#[test]
+fn long_messages_truncated() {
+    let (mut shell, capture) = Capture::shell();
+    shell.set_verbosity(Verbosity::Normal);
+    shell.set_max_message_len(Some(10));
+    shell.error("process failed: a lot of output").unwrap();
+    shell.warn("short").unwrap();
+    assert_eq!(
+        capture.contents(),
+        "error: process fa… (truncated, run with --verbose for full output)\n\
+         warning: short\n"
+    );
+
+    let (mut shell, capture) = Capture::shell();
+    shell.set_max_message_len(Some(10));
+    shell.error("process failed: a lot of output").unwrap();
+    assert_eq!(capture.contents(), "error: process failed: a lot of output\n");
+}
+
+#[test]
+fn tee_to_file() {
+    let (mut shell, capture) = Capture::shell();
+    let root = crate::util::config::test_root("tee_to_file");
+    let log = root.join("nianjia.log");
+    shell.tee_to(&log).unwrap();
+    shell.status("Compiling", "foo v0.1.0").unwrap();
+    shell.warn("unused key").unwrap();
+    shell.set_verbosity(Verbosity::Quiet);
+    shell.status("Finished", "dev").unwrap();
+    writeln!(shell.err(), "raw line").unwrap();
+    shell.err().flush().unwrap();
+
+    let expected = "   Compiling foo v0.1.0\nwarning: unused key\nraw line\n";
+    assert_eq!(capture.contents(), expected);
+    assert_eq!(std::fs::read_to_string(&log).unwrap(), expected);
 }
@@ -1,19 +1,26 @@
+pub mod cli;
 pub mod core;
 pub mod util;
 
 use failure::Error;
 use crate::core::shell::Verbosity::Verbose;
 use log::debug;
-use crate::core::shell::Shell;
+use crate::core::shell::{MessageFormat, Shell};
 pub use crate::util::errors::Internal;
 pub use crate::util::errors::CliError;
+use crate::util::config::Config;
+use crate::util::errors::{display_causes, CliResult};
+
+/// Runs the `nianjia` command line `args` (including the binary name) against
+/// `config`, returning the result instead of exiting the process.
+pub fn run(args: &[String], config: &mut Config) -> CliResult {
+    cli::main(config, args)
+}
 
 pub fn exit_with_error(err: CliError, shell: &mut Shell) -> ! {
     debug!("exit_with_error; err={:?}", err);
-    if let Some(ref err) = err.error {
-        if let Some(clap_err) = err.downcast_ref::<clap::Error>() {
-            clap_err.exit()
-        }
+    if let Some(clap_err) = err.clap_error() {
+        clap_err.exit()
     }
 
     let CliError {
@@ -25,6 +32,20 @@ pub fn exit_with_error(err: CliError, shell: &mut Shell) -> ! {
     let fatal = exit_code != 0;
 
     let hide = unknown && shell.verbosity() != Verbose;
+    drop(shell.flush_repeats());
+
+    if shell.message_format() == MessageFormat::Json {
+        let err = CliError {
+            error,
+            exit_code,
+            unknown,
+        };
+        if let Some(json) = err.to_json(shell.verbosity() == Verbose) {
+            drop(shell.error_json(json));
+        }
+        drop(shell.print_quiet_summary());
+        std::process::exit(exit_code)
+    }
 
     if let Some(error) = error {
         if hide {
@@ -44,6 +65,7 @@ pub fn exit_with_error(err: CliError, shell: &mut Shell) -> ! {
         }
     }
 
+    drop(shell.print_quiet_summary());
     std::process::exit(exit_code)
 }
 
@@ -54,27 +76,13 @@ fn handle_cause(nianjia_err: &Error, shell: &mut Shell) -> bool {
         drop(writeln!(shell.err(), "  {}", error));
     }
 
-    let verbose = shell.verbosity();
-
-    if verbose == Verbose {
-        // The first error has already been printed to the shell.
-        // Print all remaining errors.
-        for err in nianjia_err.iter_causes() {
-            print(&err.to_string(), shell);
-        }
-    } else {
-        // The first error has already been printed to the shell.
-        // Print remaining errors until one marked as `Internal` appears.
-        for err in nianjia_err.iter_causes() {
-            if err.downcast_ref::<Internal>().is_some() {
-                return false;
-            }
-
-            print(&err.to_string(), shell);
-        }
+    // The first error has already been printed to the shell. Print the
+    // remaining errors, stopping at one marked as `Internal` unless verbose.
+    let (causes, complete) = display_causes(nianjia_err, shell.verbosity() == Verbose);
+    for cause in causes {
+        print(&cause, shell);
     }
-
-    true
+    complete
 }
 
 pub const NIANJIA_ENV: &str = "NIANJIA";
@@ -3,6 +3,7 @@ use std::path::PathBuf;
 use clap::{self, SubCommand};
 
 use crate::util::config::Config;
+use crate::util::errors::NianjiaResult;
 
 pub use clap::{AppSettings, Arg, ArgMatches};
 
@@ -12,7 +13,7 @@ pub fn opt(name: &'static str, help: &'static str) -> Arg<'static, 'static> {
     Arg::with_name(name).long(name).help(help)
 }
 
-#[derive(PartialEq, PartialOrd, Eq, Ord)]
+#[derive(Clone, Debug, PartialEq, PartialOrd, Eq, Ord)]
 pub enum CommandInfo {
     BuiltIn { name: String, about: Option<String> },
     External { name: String, path: PathBuf },
@@ -33,7 +34,35 @@ pub trait ArgMatchesExt {
         self._value_of(name).map(|path| config.cwd().join(path))
     }
 
+    /// Returns value of the `name` command-line argument as a number
+    fn value_of_u32(&self, name: &str) -> NianjiaResult<Option<u32>> {
+        match self._value_of(name) {
+            None => Ok(None),
+            Some(arg) => match arg.parse::<u32>() {
+                Ok(n) => Ok(Some(n)),
+                Err(_) => failure::bail!("invalid value for --{}: `{}` is not a number", name, arg),
+            },
+        }
+    }
+
+    /// Returns whether the `name` flag was given
+    fn flag(&self, name: &str) -> bool {
+        self._is_present(name)
+    }
+
+    /// Returns `Some(true)` if the `name` flag was given and `None` if not,
+    /// leaving the decision to the configuration
+    fn maybe_flag(&self, name: &str) -> Option<bool> {
+        if self._is_present(name) {
+            Some(true)
+        } else {
+            None
+        }
+    }
+
     fn _value_of(&self, name: &str) -> Option<&str>;
+
+    fn _is_present(&self, name: &str) -> bool;
 }
 
 
@@ -41,12 +70,66 @@ impl<'a> ArgMatchesExt for ArgMatches<'a> {
     fn _value_of(&self, name: &str) -> Option<&str> {
         self.value_of(name)
     }
+
+    fn _is_present(&self, name: &str) -> bool {
+        self.is_present(name)
+    }
 }
 
+/// Help layout shared by all built-in commands.
+const SUBCOMMAND_TEMPLATE: &str = "\
+{about}
+
+USAGE:
+    {usage}
+
+{all-args}{after-help}
+";
+
 pub fn subcommand(name: &'static str) -> App {
-    SubCommand::with_name(name).settings(&[
-        AppSettings::UnifiedHelpMessage,
-        AppSettings::DeriveDisplayOrder,
-        AppSettings::DontCollapseArgsInUsage,
-    ])
+    SubCommand::with_name(name)
+        .settings(&[
+            AppSettings::UnifiedHelpMessage,
+            AppSettings::DeriveDisplayOrder,
+            AppSettings::DontCollapseArgsInUsage,
+        ])
+        .template(SUBCOMMAND_TEMPLATE)
+}
+
+#[test]
+fn subcommand_help_layout() {
+    let mut help = Vec::new();
+    subcommand("demo")
+        .about("Run a demo")
+        .arg(opt("release", "Build in release mode"))
+        .arg(Arg::with_name("args").multiple(true))
+        .write_help(&mut help)
+        .unwrap();
+    let help = String::from_utf8(help).unwrap();
+    let lines = help.lines().collect::<Vec<_>>();
+
+    assert_eq!(lines[0], "Run a demo");
+    assert_eq!(lines[2], "USAGE:");
+    assert!(lines[3].starts_with("    demo [OPTIONS] [args]..."));
+    assert!(lines.contains(&"OPTIONS:"));
+    assert!(lines.contains(&"ARGS:"));
+    assert!(help.contains("--release    Build in release mode"));
+}
+
+#[test]
+fn flags() {
+    let app = || {
+        subcommand("demo")
+            .arg(opt("quiet", "No output printed to stdout").short("q"))
+            .arg(opt("release", "Build in release mode"))
+    };
+    let args = app().get_matches_from(vec!["demo", "-q"]);
+    assert!(args.flag("quiet"));
+    assert!(!args.flag("release"));
+    assert_eq!(args.maybe_flag("quiet"), Some(true));
+    assert_eq!(args.maybe_flag("release"), None);
+
+    let args = app().get_matches_from(vec!["demo"]);
+    assert!(!args.flag("quiet"));
+    assert_eq!(args.maybe_flag("quiet"), None);
 }
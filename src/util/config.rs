@@ -1,26 +1,58 @@
 use crate::util::errors::internal;
-use std::collections::hash_map::Entry::Occupied;
-use std::collections::hash_map::Entry::Vacant;
+use std::collections::btree_map::Entry::Occupied;
+use std::collections::btree_map::Entry::Vacant;
 use std::str::FromStr;
 use std::fmt;
 use std::mem;
+use std::ops::ControlFlow;
 use std::env;
-use std::io::Read;
-use std::fs::{self, File};
-use std::collections::{HashSet, HashMap};
+use std::ffi::OsString;
+use std::io;
+use std::fs;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::cell::{RefCell, RefMut};
+use std::time::Duration;
 
 use lazycell::LazyCell;
+use serde::de::DeserializeOwned;
+use log::debug;
 
 use crate::util::toml as nianjia_toml;
-use crate::util::flock::Filesystem;
-use crate::core::shell::{Verbosity, Shell};
+use crate::util::flock::{FileLock, Filesystem};
+use crate::core::shell::{ColorChoice, ColorEnv, Verbosity, Shell};
+use crate::util::dotenv;
+use crate::util::human;
+use crate::util::job::{self, Client};
+use crate::util::lev_distance;
 use crate::util::paths;
+use crate::util::process_builder::{Executor, ProcessExecutor};
+use crate::util::watch::FileWatcher;
 use crate::util::errors::{NianjiaResult, NianjiaResultExt};
 
 use self::ConfigValue as CV;
 
+/// Prints a warning through `config`'s shell, formatting the message before
+/// borrowing the shell, so that the arguments may use it too.
+#[macro_export]
+macro_rules! config_warn {
+    ($config:expr, $($arg:tt)*) => {{
+        let message = format!($($arg)*);
+        let result = $config.shell().warn(message);
+        result
+    }};
+}
+
+/// Prints a note through `config`'s shell, like `config_warn!`.
+#[macro_export]
+macro_rules! config_note {
+    ($config:expr, $($arg:tt)*) => {{
+        let message = format!($($arg)*);
+        let result = $config.shell().note(message);
+        result
+    }};
+}
+
 /// Configuration information for nianjias. This is not specific to a build, it is information
 /// relating to nianjia itself.
 ///
@@ -32,13 +64,56 @@ pub struct Config {
     /// Information about how to write messages to the shell
     shell: RefCell<Shell>,
     /// A collection of configuration options
-    values: LazyCell<HashMap<String, ConfigValue>>,
+    values: LazyCell<BTreeMap<String, ConfigValue>>,
+    /// Values already read with `get_string` and `get_bool`, whether from
+    /// `values` or the environment, by key
+    cache: RefCell<HashMap<String, CachedValue>>,
+    /// How many times `get_cv` walked `values`
+    #[cfg(test)]
+    walks: std::cell::Cell<usize>,
     /// The current working directory of nianjia
     cwd: PathBuf,
     /// The location of the nianjia executable (path to current process)
     nianjia_exe: LazyCell<PathBuf>,
     /// Environment variables, separated to assist testing.
     env: HashMap<String, String>,
+    /// Whether to record the environment variables consulted (`--trace-config`).
+    trace_config: bool,
+    /// The `NIANJIA_*` environment variables looked up so far, if tracing.
+    consulted_env: RefCell<HashSet<String>>,
+    /// `KEY=VALUE` overrides from `--config`, in command-line order.
+    cli_config: Vec<String>,
+    /// Runs the processes of commands, replaced by a mock in tests
+    executor: Box<dyn Executor>,
+    /// The jobserver nianjia runs under, see `jobserver`
+    jobserver: LazyCell<Client>,
+    /// Canonical paths of the files from `--config-file`, in command-line
+    /// order.
+    config_files: Vec<PathBuf>,
+    /// `frozen` is set if we shouldn't access the network
+    frozen: bool,
+    /// `locked` is set if we should not update lock files
+    locked: bool,
+    /// `offline` is set if we should never access the network
+    offline: bool,
+    /// The target directory given on the command line, if any
+    target_dir: Option<Filesystem>,
+    /// The number of jobs given with `--jobs`, where `0` means all cores
+    jobs: Option<u32>,
+    /// The size above which configuration files are refused
+    max_config_file_size: u64,
+    /// Whether configuration discovery stops at the project root
+    /// (`--stop-at-project-root`)
+    stop_at_project_root: bool,
+    /// Whether processes are only reported, not run (`--dry-run`)
+    dry_run: bool,
+    /// Whether to print the configuration files as they're read (`-vv`)
+    extra_verbose: bool,
+    /// The configuration files read by the last load, in precedence order
+    loaded_config_files: RefCell<Vec<PathBuf>>,
+    /// Whether the configuration a parent nianjia resolved may be used,
+    /// cleared by `reload`
+    inherit_resolved: bool,
 }
 
 impl Config {
@@ -56,9 +131,29 @@ impl Config {
             home_path: home_path,
             shell: RefCell::new(shell),
             values: LazyCell::new(),
+            cache: RefCell::new(HashMap::new()),
+            #[cfg(test)]
+            walks: std::cell::Cell::new(0),
             cwd,
             nianjia_exe: LazyCell::new(),
-            env
+            env,
+            trace_config: false,
+            consulted_env: RefCell::new(HashSet::new()),
+            cli_config: Vec::new(),
+            config_files: Vec::new(),
+            executor: Box::new(ProcessExecutor),
+            jobserver: LazyCell::new(),
+            frozen: false,
+            locked: false,
+            offline: false,
+            target_dir: None,
+            jobs: None,
+            max_config_file_size: DEFAULT_MAX_CONFIG_FILE_SIZE,
+            stop_at_project_root: false,
+            dry_run: false,
+            extra_verbose: false,
+            loaded_config_files: RefCell::new(Vec::new()),
+            inherit_resolved: true,
         }
     }
 
@@ -89,19 +184,121 @@ impl Config {
         &self.cwd
     }
 
+    /// Replaces the environment snapshot used for `NIANJIA_*` lookups.
+    pub fn set_env(&mut self, env: HashMap<String, String>) {
+        self.env = env;
+        self.cache.get_mut().clear();
+    }
+
+    /// Gets a variable from the environment snapshot, see `set_env`.
+    pub fn env_var(&self, key: &str) -> Option<&str> {
+        self.env.get(key).map(|s| &s[..])
+    }
+
+    /// Gets the width to wrap output and draw progress in: a positive
+    /// `COLUMNS` from the environment snapshot, as set by shells that don't
+    /// export the size otherwise, or else the width of the terminal stderr
+    /// is attached to, see `Shell::err_width`.
+    pub fn term_width(&self) -> Option<usize> {
+        self.env_var("COLUMNS")
+            .and_then(|columns| columns.trim().parse().ok())
+            .filter(|&width: &usize| width > 0)
+            .or_else(|| self.shell().err_width())
+    }
+
+    /// Enables recording of the environment variables consulted for config
+    /// values, see `consulted_env_keys`.
+    pub fn set_trace_config(&mut self, trace_config: bool) {
+        self.trace_config = trace_config;
+        // Cached values wouldn't be traced.
+        self.cache.get_mut().clear();
+    }
+
+    pub fn trace_config(&self) -> bool {
+        self.trace_config
+    }
+
+    /// Sets the size in bytes above which configuration files are refused,
+    /// 1 MiB by default.
+    pub fn set_max_config_file_size(&mut self, max_bytes: u64) {
+        self.max_config_file_size = max_bytes;
+        self.clear_values();
+    }
+
+    /// Stops configuration discovery at the project root, the nearest
+    /// ancestor of the working directory holding a `.git` or a
+    /// `.nianjia/workspace`. The home directory is still read.
+    pub fn set_stop_at_project_root(&mut self, stop: bool) {
+        self.stop_at_project_root = stop;
+        self.clear_values();
+    }
+
+    /// Makes `ProcessBuilder::exec_checked` report processes instead of
+    /// running them.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Whether processes are only reported, see `set_dry_run`.
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Gets the sorted names of the `NIANJIA_*` environment variables looked up
+    /// since tracing was enabled, whether or not they were set.
+    pub fn consulted_env_keys(&self) -> Vec<String> {
+        let mut keys = self.consulted_env.borrow().iter().cloned().collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    /// Loads the nearest `.env` file found in the working directory or one of
+    /// its ancestors into the environment snapshot, if `enabled` or the
+    /// `dotenv.load` config value is set.
+    ///
+    /// Variables already present in the environment are kept unless `force`
+    /// is set. Returns the path of the file that was loaded.
+    pub fn load_dotenv(&mut self, enabled: bool, force: bool) -> NianjiaResult<Option<PathBuf>> {
+        // Errors in the configuration are reported once it's used.
+        let enabled = enabled || matches!(self.get_bool("dotenv.load"), Ok(Some(v)) if v.val);
+        if !enabled {
+            return Ok(None);
+        }
+        let path = match paths::ancestors(&self.cwd)
+            .map(|dir| dir.join(".env"))
+            .find(|path| path.is_file())
+        {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let contents = paths::read(&path)?;
+        let vars = dotenv::parse(&contents)
+            .chain_err(|| format!("could not parse `{}`", path.display()))?;
+        for (key, value) in vars {
+            if force || !self.env.contains_key(&key) {
+                self.env.insert(key, value);
+            }
+        }
+        self.cache.get_mut().clear();
+        Ok(Some(path))
+    }
+
     fn get_env<T>(&self, key: &ConfigKey) -> Result<OptValue<T>, ConfigError>
     where
         T: FromStr,
         <T as FromStr>::Err: fmt::Display,
     {
         let key = key.to_env();
+        if self.trace_config {
+            self.consulted_env.borrow_mut().insert(key.clone());
+        }
         match self.env.get(&key) {
             Some(value) => {
                 let definition = Definition::Environment(key);
                 Ok(Some(Value {
                     val: value
                         .parse()
-                        .map_err(|e| ConfigError::new(format!("{}", e), definition.clone()))?,
+                        .map_err(|e| ConfigError::new(format!("{}", e), &definition, &self.cwd))?,
                     definition,
                 }))
             }
@@ -109,8 +306,15 @@ impl Config {
         }
     }
 
+    /// Gets the value at a dotted `key` from the configuration files, whatever
+    /// its type. Environment variables are not consulted.
+    pub fn get_value(&self, key: &str) -> NianjiaResult<Option<ConfigValue>> {
+        self.get_cv(key)
+    }
 
     fn get_cv(&self, key: &str) -> NianjiaResult<Option<ConfigValue>> {
+        #[cfg(test)]
+        self.walks.set(self.walks.get() + 1);
         let vals = self.values()?;
         let mut parts = key.split('.').enumerate();
         let mut val = match vals.get(parts.next().unwrap().1) {
@@ -128,7 +332,8 @@ impl Config {
                 CV::Integer(_, ref path)
                 | CV::String(_, ref path)
                 | CV::List(_, ref path)
-                | CV::Boolean(_, ref path) => {
+                | CV::Boolean(_, ref path)
+                | CV::Datetime(_, ref path) => {
                     let idx = key.split('.').take(i).fold(0, |n, s| n + s.len()) + i - 1;
                     let key_so_far = &key[..idx];
                     failure::bail!(
@@ -144,13 +349,146 @@ impl Config {
         Ok(Some(val.clone()))
     }
 
-    pub fn values(&self) -> NianjiaResult<&HashMap<String, ConfigValue>> {
+    pub fn values(&self) -> NianjiaResult<&BTreeMap<String, ConfigValue>> {
         self.values.try_borrow_with(|| self.load_values())
     }
 
+    /// Prints each configuration file as it's read, see
+    /// `loaded_config_files`.
+    pub fn set_extra_verbose(&mut self, extra_verbose: bool) {
+        self.extra_verbose = extra_verbose;
+    }
+
+    pub fn extra_verbose(&self) -> bool {
+        self.extra_verbose
+    }
+
+    /// Gets the configuration files read when the values were loaded,
+    /// highest precedence first, ending with the home directory's config and
+    /// the credentials file.
+    pub fn loaded_config_files(&self) -> Vec<PathBuf> {
+        self.loaded_config_files.borrow().clone()
+    }
+
+    fn record_loaded(&self, path: &Path) -> NianjiaResult<()> {
+        debug!("reading configuration file `{}`", path.display());
+        self.loaded_config_files.borrow_mut().push(path.to_path_buf());
+        if self.extra_verbose {
+            writeln!(
+                self.shell().err(),
+                "{:>12} {}",
+                "Reading",
+                paths::display_relative(path, &self.cwd)
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Watches the configuration files read by the last load, see
+    /// `loaded_config_files`, loading the values first if needed.
+    pub fn watcher(&self) -> NianjiaResult<FileWatcher> {
+        self.values()?;
+        Ok(FileWatcher::new(self.loaded_config_files()))
+    }
+
+    /// Calls `on_change` whenever the configuration files read by the last
+    /// load change, until it returns `ControlFlow::Break`. Bursts of edits
+    /// are reported once.
+    ///
+    /// To pick up the changes, keep the watcher from `watcher` and call
+    /// `reload` between waits instead.
+    pub fn watch(&self, on_change: impl FnMut() -> ControlFlow<()>) -> NianjiaResult<()> {
+        self.watcher()?.watch(on_change);
+        Ok(())
+    }
+
+    pub fn frozen(&self) -> bool {
+        self.frozen
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn offline(&self) -> bool {
+        self.offline
+    }
+
+    /// Whether lock files may be updated, i.e., neither `--frozen` nor
+    /// `--locked` was given.
+    pub fn lock_available(&self) -> bool {
+        !self.frozen && !self.locked
+    }
+
+    /// Gets the target directory: `--target-dir` if given, otherwise
+    /// `build.target-dir`.
+    pub fn target_dir(&self) -> NianjiaResult<Option<Filesystem>> {
+        if let Some(dir) = &self.target_dir {
+            return Ok(Some(dir.clone()));
+        }
+        Ok(self.get_path("build.target-dir")?.map(|v| Filesystem::new(v.val)))
+    }
+
+    /// Sets the files given with `--config-file`, relative to the cwd. They
+    /// take precedence over the discovered configuration files, later ones
+    /// over earlier ones, and a discovered file given here isn't read twice.
+    pub fn set_config_files(&mut self, files: &[PathBuf]) -> NianjiaResult<()> {
+        self.config_files = files
+            .iter()
+            .map(|file| {
+                fs::canonicalize(self.cwd.join(file)).chain_err(|| {
+                    format!("failed to find configuration file `{}`", file.display())
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        self.clear_values();
+        Ok(())
+    }
+
+    /// Discards the loaded values, and the values read from them, to be
+    /// loaded again when next needed.
+    fn clear_values(&mut self) {
+        self.values = LazyCell::new();
+        self.cache.get_mut().clear();
+    }
+
+    /// Discards everything computed on demand: the loaded values and the
+    /// path to the `nianjia` executable.
+    pub fn clear_caches(&mut self) {
+        self.clear_values();
+        self.nianjia_exe = LazyCell::new();
+    }
+
+    /// Discards the loaded values and reads the configuration files again,
+    /// including the credentials file. Configuration passed down by a parent
+    /// nianjia is ignored from then on, since it may be stale.
+    pub fn reload(&mut self) -> NianjiaResult<()> {
+        self.inherit_resolved = false;
+        self.clear_values();
+        self.values()?;
+        Ok(())
+    }
+
+    /// Gets the loaded values for modification, loading them first if
+    /// needed.
+    pub fn values_mut(&mut self) -> NianjiaResult<&mut BTreeMap<String, ConfigValue>> {
+        if !self.values.filled() {
+            let values = self.load_values()?;
+            self.values.fill(values).expect("values already filled");
+        }
+        self.cache.get_mut().clear();
+        Ok(self.values.borrow_mut().expect("values not filled"))
+    }
+
     pub fn get_bool(&self, key: &str) -> NianjiaResult<OptValue<bool>> {
-        self.get_bool_priv(&ConfigKey::from_str(key))
-            .map_err(|e| e.into())
+        if let Some(CachedValue::Bool(v)) = self.cache.borrow().get(key) {
+            return Ok(v.clone());
+        }
+        let v = self.get_bool_priv(&ConfigKey::from_str(key))?;
+        self.cache
+            .borrow_mut()
+            .insert(key.to_string(), CachedValue::Bool(v.clone()));
+        Ok(v)
     }
 
     fn get_bool_priv(&self, key: &ConfigKey) -> Result<OptValue<bool>, ConfigError> {
@@ -164,7 +502,7 @@ impl Config {
                         val: b,
                         definition: Definition::Path(path),
                     })),
-                    Some(cv) => Err(ConfigError::expected(&config_key, "true/false", &cv)),
+                    Some(cv) => Err(ConfigError::expected(&config_key, "true/false", &cv, &self.cwd)),
                     None => Ok(None),
                 }
             }
@@ -172,17 +510,37 @@ impl Config {
     }
 
     fn expected<T>(&self, ty: &str, key: &str, val: &CV) -> NianjiaResult<T> {
-        val.expected(ty, key)
+        val.expected(ty, key, &self.cwd)
             .map_err(|e| failure::format_err!("invalid configuration for key `{}`\n{}", key, e))
     }
 
 
-    // NOTE: this does **not** support environment variables. Use `get` instead
-    // if you want that.
-    pub fn get_list(&self, key: &str) -> NianjiaResult<OptValue<Vec<(String, PathBuf)>>> {
+    /// Gets a list of strings, each with where it was defined.
+    ///
+    /// An environment variable overrides the list with its value split on
+    /// commas. Whitespace around each element is trimmed and empty elements
+    /// are dropped, so an empty variable gives an empty list and a trailing
+    /// comma is ignored.
+    pub fn get_list(&self, key: &str) -> NianjiaResult<OptValue<Vec<(String, Definition)>>> {
+        if let Some(v) = self.get_env::<String>(&ConfigKey::from_str(key))? {
+            let val = v
+                .val
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|s| (s.to_string(), v.definition.clone()))
+                .collect();
+            return Ok(Some(Value {
+                val,
+                definition: v.definition,
+            }));
+        }
         match self.get_cv(key)? {
             Some(CV::List(i, path)) => Ok(Some(Value {
-                val: i,
+                val: i
+                    .into_iter()
+                    .map(|(s, path)| (s, Definition::Path(path)))
+                    .collect(),
                 definition: Definition::Path(path),
             })),
             Some(val) => self.expected("list", key, &val),
@@ -190,60 +548,577 @@ impl Config {
         }
     }
 
+    /// Gets the entries of a table, such as `[alias]`. Environment variables
+    /// are not consulted.
+    pub fn get_table(&self, key: &str) -> NianjiaResult<OptValue<BTreeMap<String, ConfigValue>>> {
+        match self.get_cv(key)? {
+            Some(CV::Table(i, path)) => Ok(Some(Value {
+                val: i,
+                definition: Definition::Path(path),
+            })),
+            Some(val) => self.expected("table", key, &val),
+            None => Ok(None),
+        }
+    }
+
 
     pub fn get_string(&self, key: &str) -> NianjiaResult<OptValue<String>> {
-        self.get_string_priv(&ConfigKey::from_str(key))
+        if let Some(CachedValue::String(v)) = self.cache.borrow().get(key) {
+            return Ok(v.clone());
+        }
+        let v = self.get_string_priv(&ConfigKey::from_str(key))?;
+        self.cache
+            .borrow_mut()
+            .insert(key.to_string(), CachedValue::String(v.clone()));
+        Ok(v)
+    }
+
+    /// Gets a string that must be one of `allowed`, such as `"dev"` or
+    /// `"release"`, erroring with the allowed values otherwise.
+    pub fn get_string_enum(&self, key: &str, allowed: &[&str]) -> NianjiaResult<OptValue<String>> {
+        let v = match self.get_string(key)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        if allowed.contains(&&v.val[..]) {
+            return Ok(Some(v));
+        }
+        let mut msg = format!(
+            "invalid value for `{}` in {}: `{}`, expected one of {}",
+            key,
+            v.definition,
+            v.val,
+            allowed
+                .iter()
+                .map(|a| format!("`{}`", a))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        if let Some(closest) = lev_distance::closest(&v.val, allowed.iter()) {
+            msg.push_str(&format!("\n\n\tDid you mean `{}`?\n", closest));
+        }
+        Err(failure::err_msg(msg))
+    }
+
+    /// Gets the first of `keys` that is set, checking the environment and
+    /// then the config files for each in turn. Useful for renamed keys.
+    pub fn get_string_first(&self, keys: &[&str]) -> NianjiaResult<OptValue<String>> {
+        for key in keys {
+            if let Some(value) = self.get_string(key)? {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Deserializes the value at `key`, which may be a whole table, into `T`.
+    ///
+    /// Environment variables override the leaves set in the config files, or
+    /// `key` itself if no file sets it.
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> NianjiaResult<Option<T>> {
+        let key = ConfigKey::from_str(key);
+        let value = match self.get_cv(&key.to_config())? {
+            Some(cv) => self.with_env_overrides(&key, cv.into_toml())?,
+            None => match self.get_env::<String>(&key)? {
+                Some(v) => toml::Value::String(v.val),
+                None => return Ok(None),
+            },
+        };
+        let val = value
+            .try_into()
+            .chain_err(|| format!("could not load configuration key `{}`", key.to_config()))?;
+        Ok(Some(val))
+    }
+
+    fn with_env_overrides(&self, key: &ConfigKey, value: toml::Value) -> NianjiaResult<toml::Value> {
+        Ok(match value {
+            toml::Value::Table(table) => toml::Value::Table(
+                table
+                    .into_iter()
+                    .map(|(name, value)| {
+                        let mut key = key.clone();
+                        key.push(&name);
+                        Ok((name, self.with_env_overrides(&key, value)?))
+                    })
+                    .collect::<NianjiaResult<_>>()?,
+            ),
+            toml::Value::Integer(i) => toml::Value::Integer(self.get_env(key)?.map_or(i, |v| v.val)),
+            toml::Value::Boolean(b) => toml::Value::Boolean(self.get_env(key)?.map_or(b, |v| v.val)),
+            toml::Value::String(s) => toml::Value::String(self.get_env(key)?.map_or(s, |v| v.val)),
+            value => value,
+        })
+    }
+
+    /// Gets a path, after expanding `~` and `${VAR}` (see `expand_path`),
+    /// resolving a relative one against `Definition::root`: the directory
+    /// holding the `.nianjia` directory that set it, or the current directory
+    /// for environment variables.
+    pub fn get_path(&self, key: &str) -> NianjiaResult<OptValue<PathBuf>> {
+        let v = match self.get_string(key)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let path = self.expand_path(&v.val).chain_err(|| {
+            format!("invalid value for `{}` in {}", key, v.definition.display_in(&self.cwd))
+        })?;
+        Ok(Some(Value {
+            val: v.definition.root(self).join(path),
+            definition: v.definition,
+        }))
+    }
+
+    pub fn get_i64(&self, key: &str) -> NianjiaResult<OptValue<i64>> {
+        self.get_i64_priv(&ConfigKey::from_str(key))
             .map_err(|e| e.into())
     }
 
-    fn get_string_priv(&self, key: &ConfigKey) -> Result<OptValue<String>, ConfigError> {
+    fn get_i64_priv(&self, key: &ConfigKey) -> Result<OptValue<i64>, ConfigError> {
         match self.get_env(key)? {
             Some(v) => Ok(Some(v)),
             None => {
                 let config_key = key.to_config();
                 let o_cv = self.get_cv(&config_key)?;
                 match o_cv {
-                    Some(CV::String(s, path)) => Ok(Some(Value {
-                        val: s,
+                    Some(CV::Integer(i, path)) => Ok(Some(Value {
+                        val: i,
                         definition: Definition::Path(path),
                     })),
-                    Some(cv) => Err(ConfigError::expected(&config_key, "a string", &cv)),
+                    Some(cv) => Err(ConfigError::expected(&config_key, "an integer", &cv, &self.cwd)),
                     None => Ok(None),
                 }
             }
         }
     }
 
-     /// Loads configuration from the filesystem.
-    pub fn load_values(&self) -> NianjiaResult<HashMap<String, ConfigValue>> {
-        self.load_values_from(&self.cwd)
+    /// Gets a TOML datetime, such as `2019-05-27T07:32:00Z`, or an
+    /// environment variable in the same format.
+    pub fn get_datetime(&self, key: &str) -> NianjiaResult<OptValue<toml::value::Datetime>> {
+        let key = ConfigKey::from_str(key);
+        if let Some(v) = self.get_env(&key)? {
+            return Ok(Some(v));
+        }
+        let config_key = key.to_config();
+        match self.get_cv(&config_key)? {
+            Some(CV::Datetime(d, path)) => Ok(Some(Value {
+                val: d.parse().chain_err(|| {
+                    format!("invalid datetime for `{}` in {}", config_key, path.display())
+                })?,
+                definition: Definition::Path(path),
+            })),
+            Some(cv) => Err(ConfigError::expected(&config_key, "a datetime", &cv, &self.cwd).into()),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets a duration such as `"30s"`, see `human::parse_duration`. An
+    /// integer is in seconds.
+    pub fn get_duration(&self, key: &str) -> NianjiaResult<OptValue<Duration>> {
+        self.get_human(key, human::parse_duration, Duration::from_secs)
     }
 
-    fn load_values_from(&self, path: &Path) -> NianjiaResult<HashMap<String, ConfigValue>> {
-        let mut cfg = CV::Table(HashMap::new(), PathBuf::from("."));
-        let home = self.home_path.clone().into_path_unlocked();
+    /// Gets a size such as `"500MiB"` in bytes, see `human::parse_bytes`. An
+    /// integer is in bytes.
+    pub fn get_bytes(&self, key: &str) -> NianjiaResult<OptValue<u64>> {
+        self.get_human(key, human::parse_bytes, |bytes| bytes)
+    }
 
-        walk_tree(path, &home, |path| {
-            let mut contents = String::new();
-            let mut file = File::open(&path)?;
-            file.read_to_string(&mut contents)
-                .chain_err(|| format!("failed to read configuration file `{}`", path.display()))?;
-            let toml = nianjia_toml::parse(&contents, path, self).chain_err(|| {
-                format!("could not parse TOML configuration in `{}`", path.display())
+    fn get_human<T>(
+        &self,
+        key: &str,
+        parse: fn(&str) -> NianjiaResult<T>,
+        from_int: fn(u64) -> T,
+    ) -> NianjiaResult<OptValue<T>> {
+        let v = match self.get_string(key) {
+            Ok(Some(v)) => v,
+            Ok(None) => return Ok(None),
+            Err(_) => {
+                return match self.get_i64(key)? {
+                    Some(v) if v.val < 0 => failure::bail!(
+                        "invalid value for `{}` in {}: {} is negative",
+                        key,
+                        v.definition,
+                        v.val
+                    ),
+                    Some(v) => Ok(Some(Value {
+                        val: from_int(v.val as u64),
+                        definition: v.definition,
+                    })),
+                    None => Ok(None),
+                }
+            }
+        };
+        let val = parse(&v.val)
+            .chain_err(|| {
+                format!("invalid value for `{}` in {}", key, v.definition.display_in(&self.cwd))
             })?;
-            let value = CV::from_toml(path, toml).chain_err(|| {
-                format!(
-                    "failed to load TOML configuration from `{}`",
-                    path.display()
+        Ok(Some(Value {
+            val,
+            definition: v.definition,
+        }))
+    }
+
+    fn get_string_priv(&self, key: &ConfigKey) -> Result<OptValue<String>, ConfigError> {
+        match self.get_env(key)? {
+            Some(v) => Ok(Some(v)),
+            None => {
+                let config_key = key.to_config();
+                let o_cv = self.get_cv(&config_key)?;
+                match o_cv {
+                    Some(CV::String(s, path)) => Ok(Some(Value {
+                        val: s,
+                        definition: Definition::Path(path),
+                    })),
+                    Some(cv) => Err(ConfigError::expected(&config_key, "a string", &cv, &self.cwd)),
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+
+    /// Expands a leading `~` to the user's home directory and every `${VAR}`
+    /// to the value of environment variable `VAR` in a path from the config.
+    pub fn expand_path(&self, value: &str) -> NianjiaResult<PathBuf> {
+        let mut expanded = String::new();
+        let mut rest = value;
+        if rest == "~" || rest.starts_with("~/") || rest.starts_with("~\\") {
+            let home = self
+                .env
+                .get("HOME")
+                .map(PathBuf::from)
+                .or_else(dirs::home_dir)
+                .ok_or_else(|| failure::format_err!("cannot expand `~` in `{}`", value))?;
+            expanded.push_str(&home.to_string_lossy());
+            rest = &rest[1..];
+        }
+        while let Some(start) = rest.find("${") {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => failure::bail!("unterminated `${{` in `{}`", value),
+            };
+            let var = &rest[start + 2..end];
+            let val = self.env.get(var).ok_or_else(|| {
+                failure::format_err!(
+                    "environment variable `{}` used in `{}` is not set",
+                    var,
+                    value
                 )
             })?;
-            cfg.merge(value)
-                .chain_err(|| format!("failed to merge configuration at `{}`", path.display()))?;
-            Ok(())
+            expanded.push_str(&rest[..start]);
+            expanded.push_str(val);
+            rest = &rest[end + 1..];
+        }
+        expanded.push_str(rest);
+        Ok(PathBuf::from(expanded))
+    }
+
+    /// Gets the `Executor` that commands run their processes through.
+    pub fn executor(&self) -> &dyn Executor {
+        &*self.executor
+    }
+
+    /// Replaces the `Executor`, so that tests can see which processes a
+    /// command runs without spawning them.
+    pub fn set_executor(&mut self, executor: Box<dyn Executor>) {
+        self.executor = executor;
+    }
+
+    /// Gets the jobserver: the one of a parent process such as `make -jN`,
+    /// or else a new one with `effective_jobs` tokens.
+    pub fn jobserver(&self) -> NianjiaResult<&Client> {
+        self.jobserver.try_borrow_with(|| {
+            let jobs = self.effective_jobs()?;
+            let client = job::new_client(jobs).chain_err(|| "failed to create jobserver")?;
+            Ok::<_, failure::Error>(client)
         })
-        .chain_err(|| "could not load Nianjia configuration")?;
+    }
+
+    /// Uses `client`, such as the jobserver of a parent `make -jN` taken with
+    /// `job::client_from_env`, instead of creating one.
+    pub fn set_jobserver(&mut self, client: Client) {
+        self.jobserver = LazyCell::new();
+        drop(self.jobserver.fill(client));
+    }
+
+    /// Gets the number of parallel jobs to run: `--jobs` if given, then
+    /// `build.jobs` if configured, otherwise the available parallelism of the
+    /// machine, which is also what `--jobs 0` asks for.
+    pub fn effective_jobs(&self) -> NianjiaResult<usize> {
+        let jobs = match self.jobs {
+            Some(0) => return Ok(available_jobs()),
+            Some(jobs) => Some(jobs as i64),
+            None => self.build_config()?.jobs,
+        };
+        match jobs {
+            Some(jobs) if jobs <= 0 => {
+                failure::bail!("build.jobs must be at least 1, found {}", jobs)
+            }
+            Some(jobs) => Ok(jobs as usize),
+            None => Ok(available_jobs()),
+        }
+    }
+
+    /// Reads the `[build]` table.
+    ///
+    /// `build.target-dir` is relative to the directory containing the
+    /// `.nianjia` directory that defines it, and `build.rustflags` may be a
+    /// list or a whitespace-separated string.
+    pub fn build_config(&self) -> NianjiaResult<BuildConfig> {
+        let jobs = self.get_i64("build.jobs")?.map(|v| v.val);
+        let target_dir = self.get_path("build.target-dir")?.map(|v| v.val);
+        let rustflags = match self.get_string("build.rustflags") {
+            Ok(flags) => flags
+                .map(|v| v.val.split_whitespace().map(|s| s.to_string()).collect())
+                .unwrap_or_default(),
+            Err(_) => self
+                .get_list("build.rustflags")?
+                .map(|v| v.val.into_iter().map(|(s, _)| s).collect())
+                .unwrap_or_default(),
+        };
+        Ok(BuildConfig {
+            jobs,
+            target_dir,
+            rustflags,
+            target: self.get_string("build.target")?.map(|v| v.val),
+            incremental: self.get_bool("build.incremental")?.map(|v| v.val),
+        })
+    }
+
+    /// Looks up the `[registries.<name>]` table.
+    ///
+    /// The registry name is matched case-sensitively, both in config files
+    /// and in the `NIANJIA_REGISTRIES_<name>_*` environment variables. The
+    /// token may come from the credentials file, a config file, or the
+    /// environment. Returns `None` if the registry has no `index`.
+    pub fn registry(&self, name: &str) -> NianjiaResult<Option<Registry>> {
+        let mut key = ConfigKey::from_str("registries");
+        key.push_sensitive(name);
+
+        let mut index_key = key.clone();
+        index_key.push("index");
+        let index = match self.get_string_priv(&index_key)? {
+            Some(index) => index.val,
+            None => return Ok(None),
+        };
+
+        let mut token_key = key;
+        token_key.push("token");
+        let token = self.get_string_priv(&token_key)?.map(|t| t.val);
+
+        Ok(Some(Registry {
+            name: name.to_string(),
+            index,
+            token,
+        }))
+    }
+
+    /// Collects the registry tokens from the credentials file, the
+    /// `[registry]` and `[registries.<name>]` tables, and the
+    /// `NIANJIA_REGISTRY_TOKEN` and `NIANJIA_REGISTRIES_<name>_TOKEN`
+    /// environment variables, which take precedence.
+    pub fn credentials(&self) -> NianjiaResult<Credentials> {
+        let default = self.get_string("registry.token")?.map(|v| v.val);
+
+        let mut names = match self.get_cv("registries")? {
+            Some(CV::Table(table, _)) => table.keys().cloned().collect::<HashSet<_>>(),
+            _ => HashSet::new(),
+        };
+        names.extend(self.env.keys().filter_map(|key| {
+            let name = key.strip_prefix("NIANJIA_REGISTRIES_")?.strip_suffix("_TOKEN")?;
+            Some(name.to_string())
+        }));
+
+        let mut registries = HashMap::new();
+        for name in names {
+            let mut key = ConfigKey::from_str("registries");
+            key.push_sensitive(&name);
+            key.push("token");
+            if let Some(token) = self.get_string_priv(&key)? {
+                registries.insert(name, token.val);
+            }
+        }
+        Ok(Credentials {
+            default,
+            registries,
+        })
+    }
+
+    /// Loads configuration from the filesystem, or from the configuration a
+    /// parent nianjia resolved for the same directory, see `resolved_env`.
+    pub fn load_values(&self) -> NianjiaResult<BTreeMap<String, ConfigValue>> {
+        let values = match self.inherited_values() {
+            Some((values, files)) => {
+                *self.loaded_config_files.borrow_mut() = files;
+                let mut cfg = CV::Table(values, PathBuf::from("."));
+                self.load_credentials(&mut cfg)?;
+                match cfg {
+                    CV::Table(values, _) => values,
+                    _ => unreachable!(),
+                }
+            }
+            None => self.load_values_from(&self.cwd)?,
+        };
+        if self.cli_config.is_empty() {
+            return Ok(values);
+        }
+        let mut cli = self.cli_values()?;
+        cli.merge(CV::Table(values, PathBuf::from(".")))?;
+        match cli {
+            CV::Table(values, _) => Ok(values),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Parses the `--config` overrides into a table. Later arguments take
+    /// precedence over earlier ones.
+    fn cli_values(&self) -> NianjiaResult<ConfigValue> {
+        let path = PathBuf::from("--config");
+        let mut table = CV::Table(BTreeMap::new(), path.clone());
+        for arg in self.cli_config.iter().rev() {
+            let toml = parse_cli_config(arg)?;
+            let value = CV::from_toml(&path, toml)
+                .chain_err(|| format!("invalid --config argument `{}`", arg))?;
+            table.merge(value)?;
+        }
+        Ok(table)
+    }
+
+    /// Whether this nianjia was started by another nianjia.
+    pub fn is_nested(&self) -> bool {
+        self.env.contains_key(crate::NIANJIA_ENV)
+    }
+
+    /// Serializes the resolved configuration for `NIANJIA_RESOLVED_CONFIG`,
+    /// so that nested nianjia processes don't walk the filesystem again.
+    ///
+    /// Values from the credentials file are left out, so registry tokens
+    /// don't leak into the environment of every process nianjia runs; nested
+    /// processes read the credentials file themselves.
+    pub fn resolved_env(&self) -> NianjiaResult<String> {
+        let credentials = self.credentials_path();
+        let files = self
+            .loaded_config_files()
+            .into_iter()
+            .filter(|path| *path != credentials)
+            .collect::<Vec<_>>();
+        Ok(serde_json::json!({
+            "version": RESOLVED_CONFIG_VERSION,
+            "cwd": self.cwd,
+            "files": files,
+            "values": without_credentials(self.values()?, &credentials),
+        })
+        .to_string())
+    }
+
+    fn credentials_path(&self) -> PathBuf {
+        self.home_path.as_path_unlocked().join("credentials")
+    }
+
+    /// Computes the environment for processes nianjia runs: its own
+    /// environment, the `[env]` table, and the variables telling the child
+    /// about this nianjia.
+    ///
+    /// `[env]` entries are either strings or `{ value = "..", force = true }`
+    /// tables; only forced entries replace variables that are already set.
+    pub fn process_env(&self) -> NianjiaResult<HashMap<String, OsString>> {
+        let mut env = self
+            .env
+            .iter()
+            .map(|(k, v)| (k.clone(), OsString::from(v)))
+            .collect::<HashMap<_, _>>();
+
+        if let Some(CV::Table(table, _)) = self.get_cv("env")? {
+            for (name, value) in table {
+                let key = format!("env.{}", name);
+                let (value, force) = match value {
+                    CV::String(value, _) => (value, false),
+                    CV::Table(ref map, _) => {
+                        let value = match map.get("value") {
+                            Some(CV::String(value, _)) => value.clone(),
+                            Some(cv) => return self.expected("string", &format!("{}.value", key), cv),
+                            None => failure::bail!("missing `value` for configuration key `{}`", key),
+                        };
+                        let force = match map.get("force") {
+                            Some(&CV::Boolean(force, _)) => force,
+                            Some(cv) => return self.expected("boolean", &format!("{}.force", key), cv),
+                            None => false,
+                        };
+                        (value, force)
+                    }
+                    cv => return self.expected("string or table", &key, &cv),
+                };
+                if force || !env.contains_key(&name) {
+                    env.insert(name, value.into());
+                }
+            }
+        }
+
+        env.insert(crate::NIANJIA_ENV.to_string(), self.nianjia_exe()?.into());
+        // Too large a variable would make starting the process fail, in
+        // which case nested processes load the configuration themselves.
+        let resolved = self.resolved_env()?;
+        if resolved.len() <= RESOLVED_CONFIG_MAX_LEN {
+            env.insert(RESOLVED_CONFIG_ENV.to_string(), resolved.into());
+        }
+        Ok(env)
+    }
+
+    /// Reads the configuration passed down by a parent nianjia, along with
+    /// the files it was read from, unless it was resolved for another
+    /// directory or by an incompatible version.
+    fn inherited_values(&self) -> Option<(BTreeMap<String, ConfigValue>, Vec<PathBuf>)> {
+        #[derive(serde::Deserialize)]
+        struct Resolved {
+            version: u32,
+            cwd: PathBuf,
+            files: Vec<PathBuf>,
+            values: BTreeMap<String, ConfigValue>,
+        }
+
+        if !self.inherit_resolved || !self.is_nested() || !self.config_files.is_empty() {
+            return None;
+        }
+        let resolved = self.env.get(RESOLVED_CONFIG_ENV)?;
+        match serde_json::from_str::<Resolved>(resolved) {
+            Ok(ref r) if r.version != RESOLVED_CONFIG_VERSION || r.cwd != self.cwd => None,
+            Ok(r) => Some((r.values, r.files)),
+            Err(e) => {
+                debug!("ignoring invalid {}: {}", RESOLVED_CONFIG_ENV, e);
+                None
+            }
+        }
+    }
+
+    fn load_values_from(&self, path: &Path) -> NianjiaResult<BTreeMap<String, ConfigValue>> {
+        let mut cfg = CV::Table(BTreeMap::new(), PathBuf::from("."));
+        let home = self.home_path.as_path_unlocked();
+        self.loaded_config_files.borrow_mut().clear();
+
+        self.shell().start_warning_summary("configuration warnings");
+        let result = self.config_files.iter().rev().try_for_each(|path| {
+            if let Some(value) = self.load_file(path, &mut Vec::new())? {
+                let display = paths::display_relative(path, &self.cwd);
+                cfg.merge(value)
+                    .chain_err(|| format!("failed to merge configuration at `{}`", display))?;
+            }
+            Ok(())
+        });
+        let result = result.and_then(|()| walk_tree(self, path, home, |path| {
+            // Already loaded with higher precedence.
+            if fs::canonicalize(path).is_ok_and(|path| self.config_files.contains(&path)) {
+                return Ok(());
+            }
+            if let Some(value) = self.load_file(path, &mut Vec::new())? {
+                let display = paths::display_relative(path, &self.cwd);
+                cfg.merge(value)
+                    .chain_err(|| format!("failed to merge configuration at `{}`", display))?;
+            }
+            Ok(())
+        }));
+        self.shell().finish_warning_summary()?;
+        result.chain_err(|| "could not load Nianjia configuration")?;
 
         self.load_credentials(&mut cfg)?;
+        cfg.remove_markers();
         match cfg {
             CV::Table(map, _) => Ok(map),
             _ => unreachable!(),
@@ -251,30 +1126,100 @@ impl Config {
     }
 
 
+    /// Loads the configuration file at `path`, along with the file named by
+    /// its top-level `include` key, if any, which has lower precedence.
+    ///
+    /// `include` is relative to the including file and may use `~` and
+    /// `${VAR}`, see `expand_path`. `including` holds the files including
+    /// this one, to detect cycles. Returns `None` if the file is unreadable.
+    fn load_file(
+        &self,
+        path: &Path,
+        including: &mut Vec<PathBuf>,
+    ) -> NianjiaResult<Option<ConfigValue>> {
+        let display = paths::display_relative(path, &self.cwd);
+        let toml = nianjia_toml::parse_from_path(path, self, self.max_config_file_size);
+        // Someone else's unreadable config shouldn't break everything.
+        if let Err(ref e) = toml {
+            if let Some(e) = permission_denied(e) {
+                self.shell().warn_once(format!(
+                    "skipping unreadable configuration file `{}`: {}",
+                    display,
+                    e
+                ))?;
+                return Ok(None);
+            }
+        }
+        self.record_loaded(path)?;
+        let toml = toml.chain_err(|| {
+            format!("could not load configuration file `{}`", display)
+        })?;
+        let mut value = CV::from_toml(path, toml).chain_err(|| {
+            format!(
+                "failed to load TOML configuration from `{}`",
+                display
+            )
+        })?;
+
+        let include = match value {
+            CV::Table(ref mut table, _) => table.remove("include"),
+            _ => None,
+        };
+        let include = match include {
+            Some(CV::String(include, _)) => include,
+            Some(other) => failure::bail!(
+                "`include` in `{}` must be a string, but found {}",
+                display,
+                other.desc()
+            ),
+            None => return Ok(Some(value)),
+        };
+
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        if let Some(pos) = including.iter().position(|p| *p == canonical) {
+            let chain = including[pos..]
+                .iter()
+                .chain(Some(&canonical))
+                .map(|p| format!("`{}`", paths::display_relative(p, &self.cwd)))
+                .collect::<Vec<_>>();
+            failure::bail!("configuration files include each other: {}", chain.join(" -> "));
+        }
+        including.push(canonical);
+        let included = path
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .join(self.expand_path(&include)?);
+        let included = self
+            .load_file(&included, including)
+            .chain_err(|| format!("failed to include `{}` from `{}`", include, display))?;
+        including.pop();
+
+        if let Some(included) = included {
+            value.merge(included).chain_err(|| {
+                format!("failed to merge `{}` included from `{}`", include, display)
+            })?;
+        }
+        Ok(Some(value))
+    }
+
     /// Loads credentials config from the credentials file into the `ConfigValue` object, if
     /// present.
     fn load_credentials(&self, cfg: &mut ConfigValue) -> NianjiaResult<()> {
-        let home_path = self.home_path.clone().into_path_unlocked();
-        let credentials = home_path.join("credentials");
+        let credentials = self.credentials_path();
         if fs::metadata(&credentials).is_err() {
             return Ok(());
         }
 
-        let mut contents = String::new();
-        let mut file = File::open(&credentials)?;
-        file.read_to_string(&mut contents).chain_err(|| {
-            format!(
-                "failed to read configuration file `{}`",
-                credentials.display()
-            )
-        })?;
+        self.check_credentials_mode(cfg, &credentials)?;
+        self.record_loaded(&credentials)?;
 
-        let toml = nianjia_toml::parse(&contents, &credentials, self).chain_err(|| {
-            format!(
-                "could not parse TOML configuration in `{}`",
-                credentials.display()
-            )
-        })?;
+        let toml = nianjia_toml::parse_from_path(&credentials, self, self.max_config_file_size)
+            .chain_err(|| {
+                format!(
+                    "could not load configuration file `{}`",
+                    credentials.display()
+                )
+            })?;
 
         let mut value = CV::from_toml(&credentials, toml).chain_err(|| {
             format!(
@@ -287,12 +1232,17 @@ impl Config {
         {
             let value = match value {
                 CV::Table(ref mut value, _) => value,
-                _ => unreachable!(),
+                _ => failure::bail!(
+                    "credentials file `{}` must be a table of registries and tokens, \
+                     but found {}",
+                    credentials.display(),
+                    value.desc()
+                ),
             };
 
             if let Some(token) = value.remove("token") {
                 if let Vacant(entry) = value.entry("registry".into()) {
-                    let mut map = HashMap::new();
+                    let mut map = BTreeMap::new();
                     map.insert("token".into(), token);
                     let table = CV::Table(map, PathBuf::from("."));
                     entry.insert(table);
@@ -300,11 +1250,150 @@ impl Config {
             }
         }
 
-        // We want value to override `cfg`, so swap these.
-        mem::swap(cfg, &mut value);
-        cfg.merge(value)?;
+        // We want value to override `cfg`, so swap these.
+        mem::swap(cfg, &mut value);
+        cfg.merge(value)?;
+
+        Ok(())
+    }
+
+    /// Warns if the credentials file can be read by group or others, or
+    /// tightens it to `0600` if `registry.fix-permissions` is set.
+    #[cfg(unix)]
+    fn check_credentials_mode(&self, cfg: &ConfigValue, credentials: &Path) -> NianjiaResult<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = fs::metadata(credentials)?.permissions().mode() & 0o777;
+        if mode & 0o077 == 0 {
+            return Ok(());
+        }
+
+        // `values()` is still being loaded, so consult the partial config.
+        let fix = match self.get_env(&ConfigKey::from_str("registry.fix-permissions"))? {
+            Some(fix) => fix.val,
+            None => match cfg.lookup("registry.fix-permissions") {
+                Some(&CV::Boolean(fix, _)) => fix,
+                _ => false,
+            },
+        };
+        if fix {
+            return fs::set_permissions(credentials, fs::Permissions::from_mode(0o600))
+                .chain_err(|| format!("failed to set permissions of `{}`", credentials.display()))
+                .map_err(|e| e.into());
+        }
+
+        self.shell().warn_once(format!(
+            "credentials file `{}` is accessible by other users (mode {:o})\n\
+             run `chmod 600 {}` to protect your tokens, \
+             or set `registry.fix-permissions = true`",
+            credentials.display(),
+            mode,
+            credentials.display()
+        ))
+    }
+
+    #[cfg(not(unix))]
+    fn check_credentials_mode(&self, _cfg: &ConfigValue, _credentials: &Path) -> NianjiaResult<()> {
+        Ok(())
+    }
+
+    /// Stores `token` in the credentials file, under `[registry]` when
+    /// `registry` is `None` and under `[registries.<name>]` otherwise.
+    ///
+    /// The home directory lock is held while the file is re-read, updated and
+    /// atomically replaced, so concurrent writers don't clobber each other's
+    /// changes. Already loaded `values()` are not updated.
+    pub fn save_credentials(&self, registry: Option<&str>, token: &str) -> NianjiaResult<()> {
+        let _lock = self.acquire_home_lock()?;
+        let credentials = self.home_path.as_path_unlocked().join("credentials");
+
+        let mut toml = match fs::read_to_string(&credentials) {
+            Ok(contents) => nianjia_toml::parse(&contents, &credentials, self).chain_err(|| {
+                format!(
+                    "could not parse TOML configuration in `{}`",
+                    credentials.display()
+                )
+            })?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                toml::Value::Table(toml::value::Table::new())
+            }
+            Err(e) => {
+                return Err(failure::Error::from(e)
+                    .context(format!(
+                        "failed to read configuration file `{}`",
+                        credentials.display()
+                    ))
+                    .into())
+            }
+        };
+
+        fn table_mut<'a>(
+            value: &'a mut toml::Value,
+            key: &str,
+        ) -> NianjiaResult<&'a mut toml::Value> {
+            let table = match value {
+                toml::Value::Table(table) => table,
+                _ => failure::bail!("expected a table, but found a {}", value.type_str()),
+            };
+            let entry = table
+                .entry(key.to_string())
+                .or_insert_with(|| toml::Value::Table(toml::value::Table::new()));
+            if !entry.is_table() {
+                failure::bail!("expected `{}` to be a table, but found a {}", key, entry.type_str());
+            }
+            Ok(entry)
+        }
+
+        let table = match registry {
+            None => table_mut(&mut toml, "registry"),
+            Some(name) => table_mut(&mut toml, "registries").and_then(|t| table_mut(t, name)),
+        }
+        .chain_err(|| format!("failed to update `{}`", credentials.display()))?;
+        if let toml::Value::Table(table) = table {
+            table.insert("token".to_string(), toml::Value::String(token.to_string()));
+        }
+
+        let modes = self.file_modes(&credentials)?;
+        paths::write_atomic(&credentials, toml.to_string(), modes)
+    }
+
+    /// Gets the permissions for files and directories created at `path`.
+    ///
+    /// `fs.file-mode` and `fs.dir-mode` are octal strings such as `"0640"`.
+    /// They default to `0600`/`0700` for paths under the home directory,
+    /// which hold credentials, and `0644`/`0755` elsewhere.
+    pub fn file_modes(&self, path: &Path) -> NianjiaResult<paths::FileModes> {
+        let (file, dir) = if path.starts_with(self.home_path.as_path_unlocked()) {
+            (0o600, 0o700)
+        } else {
+            (0o644, 0o755)
+        };
+        let mode = |key: &str, default: u32| -> NianjiaResult<u32> {
+            match self.get_string(key)? {
+                Some(v) => u32::from_str_radix(v.val.trim_start_matches("0o"), 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o7777)
+                    .ok_or_else(|| {
+                        failure::format_err!(
+                            "`{}` must be an octal mode such as \"0644\", found `{}` in {}",
+                            key,
+                            v.val,
+                            v.definition.display_in(&self.cwd)
+                        )
+                    }),
+                None => Ok(default),
+            }
+        };
+        Ok(paths::FileModes {
+            file: mode("fs.file-mode", file)?,
+            dir: mode("fs.dir-mode", dir)?,
+        })
+    }
 
-        Ok(())
+    /// Acquires an exclusive lock on the Nianjia home directory, blocking
+    /// until other Nianjia processes release it.
+    pub fn acquire_home_lock(&self) -> NianjiaResult<FileLock> {
+        self.home_path.open_rw(".nianjia-lock", self, "the Nianjia home directory")
     }
 
     /// Gets the path to the `nianjia` executable.
@@ -344,6 +1433,18 @@ impl Config {
             .map(AsRef::as_ref)
     }
 
+    /// Uses `exe` as the `nianjia` executable instead of finding it, for
+    /// tests and embedders. Fails if the path was already found or set;
+    /// see `clear_caches`.
+    pub fn set_nianjia_exe(&self, exe: PathBuf) -> NianjiaResult<()> {
+        self.nianjia_exe.fill(exe).map_err(|exe| {
+            failure::format_err!(
+                "the nianjia executable is already set to `{}`",
+                self.nianjia_exe.borrow().unwrap_or(&exe).display()
+            )
+        })
+    }
+
 
     pub fn configure(
         &mut self,
@@ -354,55 +1455,138 @@ impl Config {
         locked: bool,
         target_dir: &Option<PathBuf>,
         unstable_flags: &[String],
-    ) -> NianjiaResult<()> {
+        cli_config: &[String],
+        jobs: Option<u32>,
+    ) -> NianjiaResult<ResolvedSettings> {
+        if !cli_config.is_empty() {
+            for arg in cli_config {
+                parse_cli_config(arg)?;
+            }
+            // Values may already have been read, e.g., for aliases.
+            self.cli_config = cli_config.to_vec();
+            self.clear_values();
+        }
         let extra_verbose = verbose >= 2;
         let verbose = if verbose == 0 { None } else { Some(true) };
 
         // Ignore errors in the configuration files.
         let cfg_verbose = self.get_bool("term.verbose").unwrap_or(None).map(|v| v.val);
+        let cfg_verbosity = match self.get_string("term.verbosity").unwrap_or(None) {
+            Some(v) => Some((
+                v.val.parse::<Verbosity>().chain_err(|| {
+                    format!("invalid `term.verbosity` in {}", v.definition.display_in(&self.cwd))
+                })?,
+                v.val == "trace",
+            )),
+            None => None,
+        };
         let cfg_color = self.get_string("term.color").unwrap_or(None).map(|v| v.val);
+        let cfg_quiet_summary = self.get_bool("term.quiet-summary").unwrap_or(None).map(|v| v.val);
+        let cfg_offline = self.get_bool("net.offline").unwrap_or(None).map(|v| v.val);
+        let cfg_jobs = match self.get_i64("build.jobs").unwrap_or(None) {
+            Some(jobs) if jobs.val > 0 => Some(jobs.val as u32),
+            _ => None,
+        };
+        // `--jobs 0` asks for all cores, like not configuring a number.
+        let resolved_jobs = match jobs {
+            Some(0) => None,
+            Some(jobs) => Some(jobs),
+            None => cfg_jobs,
+        };
 
         let color = color.as_ref().or_else(|| cfg_color.as_ref());
 
-        let verbosity = match (verbose, cfg_verbose, quiet) {
-            (Some(true), _, None) | (None, Some(true), None) => Verbosity::Verbose,
-
-            // Command line takes precedence over configuration, so ignore the
-            // configuration..
-            (None, _, Some(true)) => Verbosity::Quiet,
-
+        let verbosity = match (verbose, quiet) {
             // Can't pass both at the same time on the command line regardless
             // of configuration.
-            (Some(true), _, Some(true)) => {
+            (Some(true), Some(true)) => {
                 failure::bail!("cannot set both --verbose and --quiet");
             }
 
-            // Can't actually get `Some(false)` as a value from the command
-            // line, so just ignore them here to appease exhaustiveness checking
-            // in match statements.
-            (Some(false), _, _)
-            | (_, _, Some(false))
-            | (None, Some(false), None)
-            | (None, None, None) => Verbosity::Normal,
+            // Command line takes precedence over configuration, so ignore the
+            // configuration.
+            (Some(true), _) => Verbosity::Verbose,
+            (_, Some(true)) => Verbosity::Quiet,
+
+            // `term.verbosity` is more specific than `term.verbose`. Can't
+            // actually get `Some(false)` as a value from the command line, so
+            // treat it like no flag.
+            _ => match (cfg_verbosity, cfg_verbose) {
+                (Some((verbosity, _)), _) => verbosity,
+                (None, Some(true)) => Verbosity::Verbose,
+                (None, _) => Verbosity::Normal,
+            },
         };
+        let extra_verbose = extra_verbose
+            || (verbose.is_none() && quiet.is_none() && cfg_verbosity.is_some_and(|(_, t)| t));
 
+        // Not touching the network implies not updating lock files.
+        let locked = locked || frozen;
         let cli_target_dir = match target_dir.as_ref() {
             Some(dir) => Some(Filesystem::new(dir.clone())),
             None => None,
         };
 
+        let color = color.map(|s| &s[..]);
+        let color_choice = ColorChoice::from_arg(color)?;
+        let color_env = ColorEnv::from_lookup(|key| self.env_var(key).map(str::to_string));
         self.shell().set_verbosity(verbosity);
-        self.shell().set_color_choice(color.map(|s| &s[..]))?;
-        // self.extra_verbose = extra_verbose;
-        // self.frozen = frozen;
-        // self.locked = locked;
-        // self.target_dir = cli_target_dir;
+        self.shell().set_color_env(color_env);
+        self.shell().set_color_choice(color)?;
+        self.shell().set_quiet_summary(cfg_quiet_summary.unwrap_or(false));
+        self.extra_verbose = extra_verbose;
+        self.frozen = frozen;
+        self.locked = locked;
+        self.offline = cfg_offline.unwrap_or(false);
+        self.target_dir = cli_target_dir;
+        self.jobs = jobs;
         // self.cli_flags.parse(unstable_flags)?;
 
-        Ok(())
+        Ok(ResolvedSettings {
+            verbosity,
+            color: color_choice,
+            frozen,
+            locked,
+            offline: self.offline,
+            target_dir: target_dir.clone(),
+            jobs: resolved_jobs,
+        })
     }
 }
 
+/// The number of jobs the machine can run in parallel.
+fn available_jobs() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Parses a `--config KEY=VALUE` argument as a one-line TOML document.
+fn parse_cli_config(arg: &str) -> NianjiaResult<toml::Value> {
+    let toml = match arg.find('=') {
+        Some(_) => arg.parse::<toml::Value>().ok(),
+        None => None,
+    };
+    toml.ok_or_else(|| {
+        failure::format_err!(
+            "invalid --config argument `{}`: expected `KEY=VALUE` \
+             with a TOML value, e.g., `build.jobs=4` or `term.color=\"never\"`",
+            arg
+        )
+    })
+}
+
+/// The settings `Config::configure` resolved from the command line and the
+/// configuration files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedSettings {
+    pub verbosity: Verbosity,
+    pub color: ColorChoice,
+    pub frozen: bool,
+    pub locked: bool,
+    pub offline: bool,
+    pub target_dir: Option<PathBuf>,
+    pub jobs: Option<u32>,
+}
+
 pub fn homedir() -> Option<Filesystem> {
     Some(Filesystem::new(dirs::home_dir()?))
 }
@@ -448,6 +1632,14 @@ impl ConfigKey {
         )
     }
 
+    fn push(&mut self, name: &str) {
+        self.0.push(ConfigKeyPart::Part(name.to_string()));
+    }
+
+    fn push_sensitive(&mut self, name: &str) {
+        self.0.push(ConfigKeyPart::CasePart(name.to_string()));
+    }
+
     fn to_env(&self) -> String {
         format!(
             "NIANJIA_{}",
@@ -468,13 +1660,80 @@ impl ConfigKey {
     }
 }
 
-#[derive(Eq, PartialEq, Clone)]
+/// Marks a key as unset, removing a value inherited from a lower-precedence
+/// config file (such as one in a parent directory or the home directory):
+///
+/// ```toml
+/// [build]
+/// target = { __unset = true }
+/// ```
+///
+/// The table must contain nothing but `__unset = true`.
+const UNSET_MARKER: &str = "__unset";
+
+/// The environment variable through which a parent nianjia passes its
+/// resolved configuration to nested ones, see `Config::resolved_env`.
+pub const RESOLVED_CONFIG_ENV: &str = "NIANJIA_RESOLVED_CONFIG";
+
+/// Bumped whenever the format of `RESOLVED_CONFIG_ENV` changes.
+const RESOLVED_CONFIG_VERSION: u32 = 2;
+
+/// The longest `RESOLVED_CONFIG_ENV` passed on, well below the 128 KiB Linux
+/// allows for a single variable.
+const RESOLVED_CONFIG_MAX_LEN: usize = 64 * 1024;
+
+/// Copies `values` without those defined in the `credentials` file, such as
+/// the tokens of `[registry]` and `[registries.<name>]`.
+fn without_credentials(
+    values: &BTreeMap<String, ConfigValue>,
+    credentials: &Path,
+) -> BTreeMap<String, ConfigValue> {
+    values
+        .iter()
+        .filter_map(|(key, value)| {
+            let value = match value {
+                // Tables merged from several files take the path of the
+                // highest precedence one, so look at each value inside.
+                CV::Table(table, path) => {
+                    let table = without_credentials(table, credentials);
+                    if table.is_empty() && path == credentials {
+                        return None;
+                    }
+                    CV::Table(table, path.clone())
+                }
+                value if value.definition_path() == credentials => return None,
+                value => value.clone(),
+            };
+            Some((key.clone(), value))
+        })
+        .collect()
+}
+
+/// The default for `Config::set_max_config_file_size`.
+const DEFAULT_MAX_CONFIG_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Key marking a table that replaces the same table from lower-precedence
+/// config files wholesale instead of being merged with it field by field:
+///
+/// ```toml
+/// [target.custom]
+/// __replace = true
+/// runner = "qemu"
+/// ```
+const REPLACE_MARKER: &str = "__replace";
+
+#[derive(Eq, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
 pub enum ConfigValue {
     Integer(i64, PathBuf),
     String(String, PathBuf),
     List(Vec<(String, PathBuf)>, PathBuf),
-    Table(HashMap<String, ConfigValue>, PathBuf),
+    /// Keys are kept sorted rather than in declaration order: `toml` parses
+    /// tables into sorted maps already, and merging several files has no
+    /// single declaration order, so sorting is what stays deterministic.
+    Table(BTreeMap<String, ConfigValue>, PathBuf),
     Boolean(bool, PathBuf),
+    /// A TOML datetime, kept as its RFC 3339 text.
+    Datetime(String, PathBuf),
 }
 
 impl ConfigValue {
@@ -483,6 +1742,7 @@ impl ConfigValue {
             toml::Value::String(val) => Ok(CV::String(val, path.to_path_buf())),
             toml::Value::Boolean(b) => Ok(CV::Boolean(b, path.to_path_buf())),
             toml::Value::Integer(i) => Ok(CV::Integer(i, path.to_path_buf())),
+            toml::Value::Datetime(d) => Ok(CV::Datetime(d.to_string(), path.to_path_buf())),
             toml::Value::Array(val) => Ok(CV::List(
                 val.into_iter()
                     .map(|toml| match toml {
@@ -509,11 +1769,40 @@ impl ConfigValue {
         }
     }
 
+    /// Converts this value back into TOML, dropping where it was defined.
+    pub fn into_toml(self) -> toml::Value {
+        match self {
+            CV::Boolean(b, _) => toml::Value::Boolean(b),
+            CV::String(s, _) => toml::Value::String(s),
+            CV::Integer(i, _) => toml::Value::Integer(i),
+            // Only valid datetimes are stored.
+            CV::Datetime(d, _) => match d.parse() {
+                Ok(d) => toml::Value::Datetime(d),
+                Err(_) => toml::Value::String(d),
+            },
+            CV::List(list, _) => {
+                toml::Value::Array(list.into_iter().map(|(s, _)| toml::Value::String(s)).collect())
+            }
+            CV::Table(map, _) => {
+                toml::Value::Table(map.into_iter().map(|(k, v)| (k, v.into_toml())).collect())
+            }
+        }
+    }
+
+    /// Walks a dotted `key` through nested tables.
+    fn lookup(&self, key: &str) -> Option<&ConfigValue> {
+        key.split('.').try_fold(self, |cv, part| match cv {
+            CV::Table(map, _) => map.get(part),
+            _ => None,
+        })
+    }
+
     pub fn definition_path(&self) -> &Path {
         match *self {
             CV::Boolean(_, ref p)
             | CV::Integer(_, ref p)
             | CV::String(_, ref p)
+            | CV::Datetime(_, ref p)
             | CV::List(_, ref p)
             | CV::Table(_, ref p) => p,
         }
@@ -527,10 +1816,19 @@ impl ConfigValue {
                 old.extend(new.into_iter());
             }
             (&mut CV::Table(ref mut old, _), CV::Table(ref mut new, _)) => {
-                let new = mem::replace(new, HashMap::new());
+                let new = mem::replace(new, BTreeMap::new());
                 for (key, value) in new {
                     match old.entry(key.clone()) {
                         Occupied(mut entry) => {
+                            // Whichever file has precedence wins: a value
+                            // can't override an unset key and vice versa.
+                            if entry.get().is_unset_marker() || value.is_unset_marker() {
+                                continue;
+                            }
+                            // A replacing table hides everything it inherits.
+                            if entry.get().is_replace_table() {
+                                continue;
+                            }
                             let path = value.definition_path().to_path_buf();
                             let entry = entry.get_mut();
                             entry.merge(value).chain_err(|| {
@@ -568,17 +1866,54 @@ impl ConfigValue {
         Ok(())
     }
 
-    fn expected<T>(&self, wanted: &str, key: &str) -> NianjiaResult<T> {
+    /// Returns whether this is a `{ __unset = true }` table, see `UNSET_MARKER`.
+    fn is_unset_marker(&self) -> bool {
+        match *self {
+            CV::Table(ref map, _) => {
+                map.len() == 1
+                    && match map.get(UNSET_MARKER) {
+                        Some(&CV::Boolean(unset, _)) => unset,
+                        _ => false,
+                    }
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns whether this is a table marked with `REPLACE_MARKER`.
+    fn is_replace_table(&self) -> bool {
+        match *self {
+            CV::Table(ref map, _) => match map.get(REPLACE_MARKER) {
+                Some(&CV::Boolean(replace, _)) => replace,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Removes the keys unset by `UNSET_MARKER` and the `REPLACE_MARKER`
+    /// keys once all files are merged.
+    fn remove_markers(&mut self) {
+        if let CV::Table(ref mut map, _) = *self {
+            map.retain(|key, value| key != REPLACE_MARKER && !value.is_unset_marker());
+            for value in map.values_mut() {
+                value.remove_markers();
+            }
+        }
+    }
+
+    fn expected<T>(&self, wanted: &str, key: &str, cwd: &Path) -> NianjiaResult<T> {
         failure::bail!(
             "expected a {}, but found a {} for `{}` in {}",
             wanted,
             self.desc(),
             key,
-            self.definition_path().display()
+            paths::display_relative(self.definition_path(), cwd)
         )
     }
 }
 
+#[derive(Clone)]
 pub struct Value<T> {
     pub val: T,
     pub definition: Definition,
@@ -586,18 +1921,93 @@ pub struct Value<T> {
 
 pub type OptValue<T> = Option<Value<T>>;
 
-#[derive(Clone, Debug)]
+/// A value read by `Config::get_string` or `Config::get_bool`.
+#[derive(Clone)]
+enum CachedValue {
+    String(OptValue<String>),
+    Bool(OptValue<bool>),
+}
+
+impl fmt::Debug for CachedValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CachedValue::String(v) => write!(f, "{:?}", v.as_ref().map(|v| &v.val)),
+            CachedValue::Bool(v) => write!(f, "{:?}", v.as_ref().map(|v| v.val)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Definition {
     Path(PathBuf),
     Environment(String),
 }
 
+/// The `[build]` table, see `Config::build_config`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BuildConfig {
+    pub jobs: Option<i64>,
+    pub target_dir: Option<PathBuf>,
+    pub rustflags: Vec<String>,
+    pub target: Option<String>,
+    pub incremental: Option<bool>,
+}
+
+/// A registry defined in a `[registries.<name>]` table.
+pub struct Registry {
+    pub name: String,
+    pub index: String,
+    pub token: Option<String>,
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Registry")
+            .field("name", &self.name)
+            .field("index", &self.index)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+/// The registry tokens, see `Config::credentials`.
+pub struct Credentials {
+    default: Option<String>,
+    registries: HashMap<String, String>,
+}
+
+impl Credentials {
+    /// Gets the token for the default registry, from `[registry]`.
+    pub fn default_token(&self) -> Option<&str> {
+        self.default.as_ref().map(|s| &s[..])
+    }
+
+    /// Gets the token for the registry named `registry`, from
+    /// `[registries.<name>]`.
+    pub fn token_for(&self, registry: &str) -> Option<&str> {
+        self.registries.get(registry).map(|s| &s[..])
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names = self.registries.keys().collect::<Vec<_>>();
+        names.sort();
+        f.debug_struct("Credentials")
+            .field("default", &self.default.as_ref().map(|_| "<redacted>"))
+            .field("registries", &names)
+            .finish()
+    }
+}
+
 impl fmt::Debug for ConfigValue {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
             CV::Integer(i, ref path) => write!(f, "{} (from {})", i, path.display()),
             CV::Boolean(b, ref path) => write!(f, "{} (from {})", b, path.display()),
-            CV::String(ref s, ref path) => write!(f, "{} (from {})", s, path.display()),
+            CV::String(ref s, ref path) | CV::Datetime(ref s, ref path) => {
+                write!(f, "{} (from {})", s, path.display())
+            }
             CV::List(ref list, ref path) => {
                 write!(f, "[")?;
                 for (i, &(ref s, ref path)) in list.iter().enumerate() {
@@ -621,6 +2031,7 @@ impl ConfigValue {
             CV::String(..) => "string",
             CV::Boolean(..) => "boolean",
             CV::Integer(..) => "integer",
+            CV::Datetime(..) => "datetime",
         }
     }
 }
@@ -629,20 +2040,20 @@ impl ConfigValue {
 #[derive(Debug)]
 pub struct ConfigError {
     error: failure::Error,
-    definition: Option<Definition>,
+    definition: Option<String>,
 }
 
 impl std::error::Error for ConfigError {}
 
 impl ConfigError {
-    fn new(message: String, definition: Definition) -> ConfigError {
+    fn new(message: String, definition: &Definition, cwd: &Path) -> ConfigError {
         ConfigError {
             error: failure::err_msg(message),
-            definition: Some(definition),
+            definition: Some(definition.display_in(cwd)),
         }
     }
 
-    fn expected(key: &str, expected: &str, found: &ConfigValue) -> ConfigError {
+    fn expected(key: &str, expected: &str, found: &ConfigValue, cwd: &Path) -> ConfigError {
         ConfigError {
             error: failure::format_err!(
                 "`{}` expected {}, but found a {}",
@@ -650,7 +2061,7 @@ impl ConfigError {
                 expected,
                 found.desc()
             ),
-            definition: Some(Definition::Path(found.definition_path().to_path_buf())),
+            definition: Some(paths::display_relative(found.definition_path(), cwd)),
         }
     }
 }
@@ -684,6 +2095,30 @@ impl From<failure::Error> for ConfigError {
     }
 }
 
+impl Definition {
+    /// Gets the directory relative paths defined here are resolved against:
+    /// the directory containing the `.nianjia` directory of a config file, or
+    /// the current directory for environment variables.
+    pub fn root<'a>(&'a self, config: &'a Config) -> &'a Path {
+        match *self {
+            Definition::Path(ref p) => p
+                .parent()
+                .and_then(Path::parent)
+                .unwrap_or_else(|| config.cwd()),
+            Definition::Environment(_) => config.cwd(),
+        }
+    }
+
+    /// Describes this definition for messages, showing a path relative to
+    /// `cwd` when it lies under it.
+    pub fn display_in(&self, cwd: &Path) -> String {
+        match *self {
+            Definition::Path(ref p) => paths::display_relative(p, cwd),
+            Definition::Environment(_) => self.to_string(),
+        }
+    }
+}
+
 impl fmt::Display for Definition {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
@@ -693,27 +2128,1288 @@ impl fmt::Display for Definition {
     }
 }
 
-fn walk_tree<F>(pwd: &Path, home: &Path, mut walk: F) -> NianjiaResult<()>
+fn walk_tree<F>(config: &Config, pwd: &Path, home: &Path, mut walk: F) -> NianjiaResult<()>
 where
     F: FnMut(&Path) -> NianjiaResult<()>,
 {
     let mut stash: HashSet<PathBuf> = HashSet::new();
 
     for current in paths::ancestors(pwd) {
-        let possible = current.join(".nianjia").join("config");
-        if fs::metadata(&possible).is_ok() {
+        if let Some(possible) = config_file(config, &current.join(".nianjia"))? {
             walk(&possible)?;
             stash.insert(possible);
         }
+        if config.stop_at_project_root && is_project_root(current) {
+            break;
+        }
     }
 
     // Once we're done, also be sure to walk the home directory even if it's not
     // in our history to be sure we pick up that standard location for
     // information.
-    let config = home.join("config");
-    if !stash.contains(&config) && fs::metadata(&config).is_ok() {
-        walk(&config)?;
+    if let Some(config_path) = config_file(config, home)? {
+        if !stash.contains(&config_path) {
+            walk(&config_path)?;
+        }
     }
 
     Ok(())
 }
+
+/// Gets the error behind `err` if it is a lack of permission to read a file.
+fn permission_denied(err: &failure::Error) -> Option<&io::Error> {
+    err.iter_chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .find(|e| e.kind() == io::ErrorKind::PermissionDenied)
+}
+
+/// Whether `dir` is the root of a project: a repository or a workspace.
+fn is_project_root(dir: &Path) -> bool {
+    dir.join(".git").exists() || dir.join(".nianjia").join("workspace").exists()
+}
+
+/// Finds the configuration file in `dir`: `config`, or `config.toml` for
+/// editors that go by the extension. The extensionless file wins if both
+/// exist.
+fn config_file(config: &Config, dir: &Path) -> NianjiaResult<Option<PathBuf>> {
+    let plain = dir.join("config");
+    let with_ext = dir.join("config.toml");
+    match (fs::metadata(&plain).is_ok(), fs::metadata(&with_ext).is_ok()) {
+        (true, true) => {
+            config.shell().warn_once(format!(
+                "both `{}` and `{}` exist, using `{}`",
+                paths::display_relative(&plain, config.cwd()),
+                paths::display_relative(&with_ext, config.cwd()),
+                paths::display_relative(&plain, config.cwd()),
+            ))?;
+            Ok(Some(plain))
+        }
+        (true, false) => Ok(Some(plain)),
+        (false, true) => Ok(Some(with_ext)),
+        (false, false) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn test_root(name: &str) -> PathBuf {
+    let root = env::temp_dir()
+        .join("nianjia-tests")
+        .join(format!("{}-{}", name, std::process::id()));
+    drop(fs::remove_dir_all(&root));
+    fs::create_dir_all(root.join("home")).unwrap();
+    fs::create_dir_all(root.join("cwd").join(".nianjia")).unwrap();
+    root
+}
+
+/// Builds a `Config` rooted at `root` with `contents` as its `.nianjia/config`
+/// and an empty environment.
+#[cfg(test)]
+fn test_config(root: &Path, contents: &str) -> Config {
+    fs::write(root.join("cwd").join(".nianjia").join("config"), contents).unwrap();
+    let mut config = Config::new(
+        Shell::from_write(Box::new(io::sink())),
+        root.join("cwd"),
+        Filesystem::new(root.join("home")),
+    );
+    config.set_env(HashMap::new());
+    config
+}
+
+#[test]
+fn registry_token_from_env() {
+    let root = test_root("registry_token_from_env");
+    let mut config = test_config(
+        &root,
+        "[registries.MyReg]\nindex = \"https://example.com/index\"\n",
+    );
+    let mut env = HashMap::new();
+    env.insert("NIANJIA_REGISTRIES_MyReg_TOKEN".to_string(), "sekrit".to_string());
+    config.set_env(env);
+
+    let registry = config.registry("MyReg").unwrap().unwrap();
+    assert_eq!(registry.index, "https://example.com/index");
+    assert_eq!(registry.token.as_ref().map(|s| &s[..]), Some("sekrit"));
+    assert!(!format!("{:?}", registry).contains("sekrit"));
+    assert!(config.registry("myreg").unwrap().is_none());
+}
+
+#[test]
+fn registry_token_from_credentials() {
+    let root = test_root("registry_token_from_credentials");
+    let config = test_config(
+        &root,
+        "[registries.myreg]\nindex = \"https://example.com/index\"\n",
+    );
+    fs::write(
+        root.join("home").join("credentials"),
+        "[registries.myreg]\ntoken = \"from-file\"\n",
+    )
+    .unwrap();
+
+    let registry = config.registry("myreg").unwrap().unwrap();
+    assert_eq!(registry.name, "myreg");
+    assert_eq!(registry.token.as_ref().map(|s| &s[..]), Some("from-file"));
+}
+
+#[test]
+fn configure_resolves_settings() {
+    let root = test_root("configure_resolves_settings");
+    let mut config = test_config(
+        &root,
+        "[term]\ncolor = \"never\"\n[net]\noffline = true\n[build]\njobs = 4\n",
+    );
+    let target_dir = Some(root.join("target"));
+    let settings = config
+        .configure(1, None, &None, true, false, &target_dir, &[], &[], None)
+        .unwrap();
+    assert_eq!(settings.verbosity, Verbosity::Verbose);
+    assert_eq!(settings.color, ColorChoice::Never);
+    assert!(settings.frozen);
+    assert!(settings.locked);
+    assert!(settings.offline);
+    assert_eq!(settings.target_dir, target_dir);
+    assert_eq!(settings.jobs, Some(4));
+    assert!(config.frozen() && config.locked() && config.offline());
+    assert!(!config.lock_available());
+    assert_eq!(
+        config.target_dir().unwrap().map(|t| t.into_path_unlocked()),
+        target_dir
+    );
+
+    let settings = config
+        .configure(0, Some(true), &Some("always".to_string()), false, true, &None, &[], &[], None)
+        .unwrap();
+    assert_eq!(settings.verbosity, Verbosity::Quiet);
+    assert_eq!(settings.color, ColorChoice::Always);
+    assert!(settings.locked);
+    assert_eq!(settings.target_dir, None);
+    assert!(!config.frozen() && config.locked());
+    assert!(config.target_dir().unwrap().is_none());
+
+    config
+        .configure(0, None, &None, false, false, &None, &[], &[], None)
+        .unwrap();
+    assert!(config.lock_available());
+
+    assert!(config
+        .configure(1, Some(true), &None, false, false, &None, &[], &[], None)
+        .is_err());
+}
+
+#[test]
+fn concurrent_credential_writes() {
+    use std::thread;
+
+    let root = test_root("concurrent_credential_writes");
+    test_config(&root, "");
+    let writers = (0..4)
+        .map(|i| {
+            let root = root.clone();
+            thread::spawn(move || {
+                let config = Config::new(
+                    Shell::from_write(Box::new(io::sink())),
+                    root.join("cwd"),
+                    Filesystem::new(root.join("home")),
+                );
+                for round in 0..10 {
+                    let token = format!("token-{}-{}", i, round);
+                    config
+                        .save_credentials(Some(&format!("reg{}", i)), &token)
+                        .unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    let config = test_config(&root, "");
+    for i in 0..4 {
+        let token = config
+            .get_string(&format!("registries.reg{}.token", i))
+            .unwrap()
+            .unwrap();
+        assert_eq!(token.val, format!("token-{}-9", i));
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn world_readable_credentials() {
+    use crate::core::shell::Capture;
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = test_root("world_readable_credentials");
+    let credentials = root.join("home").join("credentials");
+    fs::write(&credentials, "[registry]\ntoken = \"sekrit\"\n").unwrap();
+    fs::set_permissions(&credentials, fs::Permissions::from_mode(0o644)).unwrap();
+
+    let config = test_config(&root, "");
+    let (shell, capture) = Capture::shell();
+    *config.shell() = shell;
+    config.values().unwrap();
+    assert!(capture.contents().contains("is accessible by other users (mode 644)"));
+    assert!(capture.contents().contains("chmod 600"));
+
+    let config = test_config(&root, "[registry]\nfix-permissions = true\n");
+    let (shell, capture) = Capture::shell();
+    *config.shell() = shell;
+    config.values().unwrap();
+    assert_eq!(capture.contents(), "");
+    let mode = fs::metadata(&credentials).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+#[test]
+fn trace_config_records_env_keys() {
+    let root = test_root("trace_config_records_env_keys");
+    let mut config = test_config(&root, "[term]\nverbose = true\n");
+    config.get_bool("term.verbose").unwrap();
+    assert!(config.consulted_env_keys().is_empty());
+
+    config.set_trace_config(true);
+    config.get_bool("term.verbose").unwrap();
+    config.get_string("build.target-dir").unwrap();
+    config.registry("MyReg").unwrap();
+    assert_eq!(
+        config.consulted_env_keys(),
+        [
+            "NIANJIA_BUILD_TARGET_DIR",
+            "NIANJIA_REGISTRIES_MyReg_INDEX",
+            "NIANJIA_TERM_VERBOSE",
+        ]
+    );
+}
+
+#[test]
+fn permission_denied_cause() {
+    let denied = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+    let err = failure::Error::from(denied).context("failed to read `config`").into();
+    assert_eq!(permission_denied(&err).unwrap().to_string(), "denied");
+    let missing = io::Error::new(io::ErrorKind::NotFound, "missing");
+    assert!(permission_denied(&missing.into()).is_none());
+}
+
+#[cfg(unix)]
+#[test]
+#[ignore = "permissions don't apply to root; run with --ignored as a regular user"]
+fn unreadable_ancestor_config() {
+    use crate::core::shell::Capture;
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = test_root("unreadable_ancestor_config");
+    let ancestor = root.join(".nianjia").join("config");
+    fs::create_dir_all(ancestor.parent().unwrap()).unwrap();
+    fs::write(&ancestor, "[term]\nverbose = true\n").unwrap();
+    fs::set_permissions(&ancestor, fs::Permissions::from_mode(0o000)).unwrap();
+
+    let config = test_config(&root, "[term]\ncolor = \"never\"\n");
+    let (shell, capture) = Capture::shell();
+    *config.shell() = shell;
+    let color = config.get_string("term.color").unwrap().unwrap();
+    assert_eq!(color.val, "never");
+    assert!(config.get_bool("term.verbose").unwrap().is_none());
+    assert!(capture
+        .contents()
+        .contains("skipping unreadable configuration file"));
+}
+
+#[test]
+fn build_config_section() {
+    let root = test_root("build_config_section");
+    let mut config = test_config(
+        &root,
+        "[build]\n\
+         jobs = 4\n\
+         target-dir = \"out\"\n\
+         rustflags = [\"-C\", \"opt-level=3\"]\n\
+         target = \"x86_64-unknown-linux-gnu\"\n\
+         incremental = false\n",
+    );
+    let build = config.build_config().unwrap();
+    assert_eq!(build.jobs, Some(4));
+    assert_eq!(build.incremental, Some(false));
+    assert_eq!(build.target_dir, Some(root.join("cwd").join("out")));
+    assert_eq!(build.rustflags, ["-C", "opt-level=3"]);
+    assert_eq!(build.target.as_ref().map(|s| &s[..]), Some("x86_64-unknown-linux-gnu"));
+
+    let mut env = HashMap::new();
+    env.insert("NIANJIA_BUILD_JOBS".to_string(), "2".to_string());
+    env.insert("NIANJIA_BUILD_RUSTFLAGS".to_string(), "-g  -Dwarnings".to_string());
+    config.set_env(env);
+    let build = config.build_config().unwrap();
+    assert_eq!(build.jobs, Some(2));
+    assert_eq!(build.rustflags, ["-g", "-Dwarnings"]);
+
+    let config = test_config(&root, "[build]\nrustflags = \"-C  debuginfo=2\"\n");
+    let build = config.build_config().unwrap();
+    assert_eq!(build.rustflags, ["-C", "debuginfo=2"]);
+    assert_eq!(build.incremental, None);
+    assert_eq!(build.target_dir, None);
+}
+
+#[test]
+fn expand_path_tilde_and_env() {
+    let root = test_root("expand_path_tilde_and_env");
+    let mut config = test_config(&root, "");
+    let mut env = HashMap::new();
+    env.insert("HOME".to_string(), "/home/me".to_string());
+    env.insert("NIANJIA_SHARED".to_string(), "/srv/shared".to_string());
+    config.set_env(env);
+
+    let expanded = config.expand_path("${NIANJIA_SHARED}/common.toml").unwrap();
+    assert_eq!(expanded, PathBuf::from("/srv/shared/common.toml"));
+    let expanded = config.expand_path("~/nianjia/${NIANJIA_SHARED}").unwrap();
+    assert_eq!(expanded, PathBuf::from("/home/me/nianjia//srv/shared"));
+    assert_eq!(config.expand_path("a~b").unwrap(), PathBuf::from("a~b"));
+
+    let err = config.expand_path("${NOPE}/x").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "environment variable `NOPE` used in `${NOPE}/x` is not set"
+    );
+    assert!(config.expand_path("${NIANJIA_SHARED").is_err());
+}
+
+#[test]
+fn get_path_expands_home_and_vars() {
+    let root = test_root("get_path_expands_home_and_vars");
+    let mut config = test_config(
+        &root,
+        "[paths]\nhome = \"~/out\"\nvar = \"${OUT}/x\"\nrel = \"out/${NAME}\"\nbad = \"${NOPE}\"\n",
+    );
+    let mut env = HashMap::new();
+    env.insert("HOME".to_string(), "/home/me".to_string());
+    env.insert("OUT".to_string(), "/srv/out".to_string());
+    env.insert("NAME".to_string(), "debug".to_string());
+    config.set_env(env);
+
+    let get = |key| config.get_path(key).unwrap().unwrap().val;
+    assert_eq!(get("paths.home"), PathBuf::from("/home/me/out"));
+    assert_eq!(get("paths.var"), PathBuf::from("/srv/out/x"));
+    assert_eq!(get("paths.rel"), root.join("cwd").join("out").join("debug"));
+    let err = config.get_path("paths.bad").err().unwrap();
+    assert_eq!(err.to_string(), "invalid value for `paths.bad` in ./.nianjia/config");
+}
+
+#[test]
+fn unset_marker_removes_inherited_key() {
+    let root = test_root("unset_marker_removes_inherited_key");
+    let parent = root.join(".nianjia").join("config");
+    fs::create_dir_all(parent.parent().unwrap()).unwrap();
+    fs::write(&parent, "[build]\ntarget = \"wasm32\"\njobs = 2\n").unwrap();
+
+    let config = test_config(&root, "[build]\ntarget = { __unset = true }\n");
+    assert!(config.get_string("build.target").unwrap().is_none());
+    assert_eq!(config.build_config().unwrap().jobs, Some(2));
+    match config.values().unwrap().get("build") {
+        Some(CV::Table(build, _)) => assert!(!build.contains_key("target")),
+        other => panic!("unexpected build table {:?}", other),
+    }
+
+    // An unset in a lower-precedence file doesn't affect the higher one.
+    fs::write(&parent, "[build]\ntarget = { __unset = true }\n").unwrap();
+    let config = test_config(&root, "[build]\ntarget = \"x86_64\"\n");
+    assert_eq!(config.get_string("build.target").unwrap().unwrap().val, "x86_64");
+}
+
+#[test]
+fn dotenv_precedence() {
+    let root = test_root("dotenv_precedence");
+    fs::write(
+        root.join(".env"),
+        "NIANJIA_BUILD_JOBS=3\nNIANJIA_TERM_COLOR=never\n",
+    )
+    .unwrap();
+    let mut config = test_config(&root, "[dotenv]\nload = true\n");
+    let mut env = HashMap::new();
+    env.insert("NIANJIA_TERM_COLOR".to_string(), "always".to_string());
+    config.set_env(env.clone());
+
+    assert_eq!(config.load_dotenv(false, false).unwrap(), Some(root.join(".env")));
+    assert_eq!(config.build_config().unwrap().jobs, Some(3));
+    assert_eq!(config.get_string("term.color").unwrap().unwrap().val, "always");
+
+    config.set_env(env);
+    config.load_dotenv(false, true).unwrap();
+    assert_eq!(config.get_string("term.color").unwrap().unwrap().val, "never");
+
+    let mut config = test_config(&root, "[dotenv\n");
+    assert_eq!(config.load_dotenv(false, false).unwrap(), None);
+    assert!(config.values().is_err());
+}
+
+#[test]
+fn get_string_first_falls_back() {
+    let root = test_root("get_string_first_falls_back");
+    let config = test_config(&root, "[term]\ncolour = \"never\"\n");
+    let value = config
+        .get_string_first(&["term.color", "term.colour", "term.colors"])
+        .unwrap()
+        .unwrap();
+    assert_eq!(value.val, "never");
+    assert_eq!(
+        config.get_string_first(&["term.color", "term.colors"]).unwrap().map(|v| v.val),
+        None
+    );
+}
+
+#[test]
+fn effective_jobs_sources() {
+    let root = test_root("effective_jobs_sources");
+    let mut config = test_config(&root, "[build]\njobs = 3\n");
+    assert_eq!(config.effective_jobs().unwrap(), 3);
+
+    let mut env = HashMap::new();
+    env.insert("NIANJIA_BUILD_JOBS".to_string(), "5".to_string());
+    config.set_env(env.clone());
+    assert_eq!(config.effective_jobs().unwrap(), 5);
+
+    env.insert("NIANJIA_BUILD_JOBS".to_string(), "0".to_string());
+    config.set_env(env);
+    assert!(config.effective_jobs().is_err());
+
+    let config = test_config(&root, "");
+    assert!(config.effective_jobs().unwrap() >= 1);
+}
+
+#[test]
+fn cli_jobs_wins() {
+    let root = test_root("cli_jobs_wins");
+    let mut config = test_config(&root, "[build]\njobs = 3\n");
+    let settings = config
+        .configure(0, None, &None, false, false, &None, &[], &[], Some(7))
+        .unwrap();
+    assert_eq!(settings.jobs, Some(7));
+    assert_eq!(config.effective_jobs().unwrap(), 7);
+
+    let settings = config
+        .configure(0, None, &None, false, false, &None, &[], &[], Some(0))
+        .unwrap();
+    assert_eq!(settings.jobs, None);
+    assert_eq!(config.effective_jobs().unwrap(), available_jobs());
+}
+
+#[test]
+fn replace_marker_replaces_inherited_table() {
+    let root = test_root("replace_marker_replaces_inherited_table");
+    let parent = root.join(".nianjia").join("config");
+    fs::create_dir_all(parent.parent().unwrap()).unwrap();
+    fs::write(&parent, "[target.custom]\nrunner = \"qemu\"\nlinker = \"cc\"\n").unwrap();
+
+    let config = test_config(&root, "[target.custom]\nrunner = \"wine\"\n");
+    assert_eq!(config.get_string("target.custom.runner").unwrap().unwrap().val, "wine");
+    assert_eq!(config.get_string("target.custom.linker").unwrap().unwrap().val, "cc");
+
+    let config = test_config(&root, "[target.custom]\n__replace = true\nrunner = \"wine\"\n");
+    assert_eq!(config.get_string("target.custom.runner").unwrap().unwrap().val, "wine");
+    assert!(config.get_string("target.custom.linker").unwrap().is_none());
+    assert!(config.get_bool("target.custom.__replace").unwrap().is_none());
+}
+
+#[cfg(unix)]
+#[test]
+fn credentials_written_private() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = test_root("credentials_written_private");
+    let config = test_config(&root, "");
+    config.save_credentials(None, "secret").unwrap();
+    let mode = fs::metadata(root.join("home").join("credentials"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let modes = config.file_modes(&root.join("cwd").join("out")).unwrap();
+    assert_eq!(modes, paths::FileModes { file: 0o644, dir: 0o755 });
+    let config = test_config(&root, "[fs]\nfile-mode = \"0640\"\ndir-mode = \"bad\"\n");
+    assert!(config.file_modes(&root.join("cwd")).is_err());
+}
+
+#[test]
+fn nested_config_from_parent() {
+    let root = test_root("nested_config_from_parent");
+    let parent = test_config(&root, "[build]\njobs = 4\n[alias]\nb = \"build\"\n");
+    let resolved = parent.resolved_env().unwrap();
+    fs::remove_file(root.join("cwd").join(".nianjia").join("config")).unwrap();
+
+    let mut child = test_config(&root, "[build]\njobs = 1\n");
+    let mut env = HashMap::new();
+    env.insert("NIANJIA".to_string(), "/bin/nianjia".to_string());
+    env.insert(RESOLVED_CONFIG_ENV.to_string(), resolved.clone());
+    child.set_env(env.clone());
+    assert_eq!(child.values().unwrap(), parent.values().unwrap());
+    assert_eq!(child.build_config().unwrap().jobs, Some(4));
+
+    // An incompatible version makes the child read its own files.
+    let stale = resolved.replace("\"version\":2", "\"version\":0");
+    env.insert(RESOLVED_CONFIG_ENV.to_string(), stale);
+    let mut child = test_config(&root, "[build]\njobs = 1\n");
+    child.set_env(env);
+    assert_eq!(child.build_config().unwrap().jobs, Some(1));
+}
+
+#[test]
+fn get_i64_sources() {
+    let root = test_root("get_i64_sources");
+    let mut config = test_config(&root, "[net]\nretry = 3\nname = \"x\"\n");
+    assert_eq!(config.get_i64("net.retry").unwrap().unwrap().val, 3);
+    assert!(config.get_i64("net.missing").unwrap().is_none());
+
+    let err = config.get_i64("net.name").err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "error in ./.nianjia/config: `net.name` expected an integer, but found a string"
+    );
+
+    let mut env = HashMap::new();
+    env.insert("NIANJIA_NET_RETRY".to_string(), "-4".to_string());
+    config.set_env(env);
+    assert_eq!(config.get_i64("net.retry").unwrap().unwrap().val, -4);
+}
+
+#[test]
+fn get_deserializes_table() {
+    #[derive(serde::Deserialize, Debug, PartialEq)]
+    #[serde(rename_all = "kebab-case")]
+    struct Registry {
+        index: String,
+        default: Option<String>,
+        net: Net,
+    }
+
+    #[derive(serde::Deserialize, Debug, PartialEq)]
+    struct Net {
+        retries: i64,
+        offline: bool,
+        mirrors: Vec<String>,
+    }
+
+    let root = test_root("get_deserializes_table");
+    let mut config = test_config(
+        &root,
+        "[registry]\n\
+         index = \"https://example.com/index\"\n\
+         [registry.net]\n\
+         retries = 2\n\
+         offline = false\n\
+         mirrors = [\"a\", \"b\"]\n",
+    );
+    let mut env = HashMap::new();
+    env.insert("NIANJIA_REGISTRY_NET_RETRIES".to_string(), "5".to_string());
+    config.set_env(env);
+
+    let registry: Registry = config.get("registry").unwrap().unwrap();
+    assert_eq!(
+        registry,
+        Registry {
+            index: "https://example.com/index".to_string(),
+            default: None,
+            net: Net {
+                retries: 5,
+                offline: false,
+                mirrors: vec!["a".to_string(), "b".to_string()],
+            },
+        }
+    );
+    assert!(config.get::<Registry>("missing").unwrap().is_none());
+    assert!(config.get::<Net>("registry").is_err());
+}
+
+#[test]
+fn process_env_merges() {
+    let root = test_root("process_env_merges");
+    let mut config = test_config(
+        &root,
+        "[env]\n\
+         load-dotenv = \"yes\"\n\
+         GREETING = \"hello\"\n\
+         PATH = \"/ignored\"\n\
+         HOME = { value = \"/forced\", force = true }\n",
+    );
+    let mut env = HashMap::new();
+    env.insert("PATH".to_string(), "/bin".to_string());
+    env.insert("HOME".to_string(), "/home/me".to_string());
+    config.set_env(env);
+
+    let env = config.process_env().unwrap();
+    assert_eq!(env["GREETING"], "hello");
+    assert_eq!(env["PATH"], "/bin");
+    assert_eq!(env["HOME"], "/forced");
+    assert_eq!(Path::new(&env["NIANJIA"]), config.nianjia_exe().unwrap());
+    assert!(env[RESOLVED_CONFIG_ENV].to_str().unwrap().contains("GREETING"));
+    assert_eq!(env["load-dotenv"], "yes");
+}
+
+#[test]
+fn get_list_from_env() {
+    let root = test_root("get_list_from_env");
+    let mut config = test_config(&root, "[build]\nflags = [\"-a\"]\n");
+    let list = |config: &Config| {
+        config
+            .get_list("build.flags")
+            .unwrap()
+            .unwrap()
+            .val
+            .into_iter()
+            .map(|(s, _)| s)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(list(&config), ["-a"]);
+
+    let mut env = HashMap::new();
+    for (value, expected) in &[("", &[][..]), ("-b", &["-b"][..]), (" -b , -c,", &["-b", "-c"][..])] {
+        env.insert("NIANJIA_BUILD_FLAGS".to_string(), value.to_string());
+        config.set_env(env.clone());
+        assert_eq!(list(&config), *expected);
+    }
+    let (_, definition) = &config.get_list("build.flags").unwrap().unwrap().val[0];
+    assert_eq!(definition, &Definition::Environment("NIANJIA_BUILD_FLAGS".to_string()));
+}
+
+#[test]
+fn get_path_resolution() {
+    let root = test_root("get_path_resolution");
+    let mut config = test_config(&root, "[paths]\nrel = \"out/dir\"\nabs = \"/opt/out\"\n");
+    assert_eq!(
+        config.get_path("paths.rel").unwrap().unwrap().val,
+        root.join("cwd").join("out/dir")
+    );
+    if cfg!(unix) {
+        assert_eq!(config.get_path("paths.abs").unwrap().unwrap().val, Path::new("/opt/out"));
+    }
+
+    let mut env = HashMap::new();
+    env.insert("NIANJIA_PATHS_REL".to_string(), "env/dir".to_string());
+    config.set_env(env);
+    let value = config.get_path("paths.rel").unwrap().unwrap();
+    assert_eq!(value.val, config.cwd().join("env/dir"));
+    assert_eq!(value.definition, Definition::Environment("NIANJIA_PATHS_REL".to_string()));
+}
+
+#[test]
+fn cli_config_overrides_files() {
+    let root = test_root("cli_config_overrides_files");
+    let mut config = test_config(&root, "[build]\njobs = 2\ntarget = \"x86\"\n");
+    assert_eq!(config.get_i64("build.jobs").unwrap().unwrap().val, 2);
+
+    let args = ["build.jobs=3".to_string(), "build.jobs=4".to_string()];
+    let settings = config
+        .configure(0, None, &None, false, false, &None, &[], &args, None)
+        .unwrap();
+    assert_eq!(settings.jobs, Some(4));
+    assert_eq!(config.get_string("build.target").unwrap().unwrap().val, "x86");
+
+    let mut env = HashMap::new();
+    env.insert("NIANJIA_BUILD_JOBS".to_string(), "5".to_string());
+    config.set_env(env);
+    assert_eq!(config.get_i64("build.jobs").unwrap().unwrap().val, 5);
+
+    let err = config
+        .configure(0, None, &None, false, false, &None, &[], &["build.jobs".to_string()], None)
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("`build.jobs`"), "{}", err);
+}
+
+#[test]
+fn reload_picks_up_changes() {
+    let root = test_root("reload_picks_up_changes");
+    let mut config = test_config(&root, "[build]\ntarget = \"x86\"\n");
+    assert_eq!(config.get_string("build.target").unwrap().unwrap().val, "x86");
+
+    let path = root.join("cwd").join(".nianjia").join("config");
+    fs::write(&path, "[build]\ntarget = \"arm\"\n").unwrap();
+    assert_eq!(config.get_string("build.target").unwrap().unwrap().val, "x86");
+    config.reload().unwrap();
+    assert_eq!(config.get_string("build.target").unwrap().unwrap().val, "arm");
+
+    config
+        .values_mut()
+        .unwrap()
+        .insert("injected".to_string(), CV::Boolean(true, path));
+    assert!(config.get_bool("injected").unwrap().unwrap().val);
+}
+
+#[test]
+fn credentials_tokens() {
+    let root = test_root("credentials_tokens");
+    fs::write(
+        root.join("home").join("credentials"),
+        "[registry]\ntoken = \"default-sekrit\"\n\
+         [registries.alt]\ntoken = \"alt-sekrit\"\n",
+    )
+    .unwrap();
+    let mut config = test_config(&root, "[registries.other]\ntoken = \"other-sekrit\"\n");
+
+    let credentials = config.credentials().unwrap();
+    assert_eq!(credentials.default_token(), Some("default-sekrit"));
+    assert_eq!(credentials.token_for("alt"), Some("alt-sekrit"));
+    assert_eq!(credentials.token_for("other"), Some("other-sekrit"));
+    assert_eq!(credentials.token_for("missing"), None);
+    assert!(!format!("{:?}", credentials).contains("sekrit"));
+
+    let mut env = HashMap::new();
+    env.insert("NIANJIA_REGISTRIES_alt_TOKEN".to_string(), "env-alt".to_string());
+    env.insert("NIANJIA_REGISTRIES_Env_TOKEN".to_string(), "env-only".to_string());
+    env.insert("NIANJIA_REGISTRY_TOKEN".to_string(), "env-default".to_string());
+    config.set_env(env);
+    let credentials = config.credentials().unwrap();
+    assert_eq!(credentials.default_token(), Some("env-default"));
+    assert_eq!(credentials.token_for("alt"), Some("env-alt"));
+    assert_eq!(credentials.token_for("Env"), Some("env-only"));
+}
+
+#[test]
+fn extra_verbose_lists_config_files() {
+    use crate::core::shell::Capture;
+
+    let root = test_root("extra_verbose_lists_config_files");
+    fs::create_dir_all(root.join("cwd").join("sub").join(".nianjia")).unwrap();
+    fs::write(root.join("cwd").join("sub").join(".nianjia").join("config"), "").unwrap();
+    fs::write(root.join("home").join("config"), "").unwrap();
+    fs::write(root.join("home").join("credentials"), "").unwrap();
+    test_config(&root, "");
+
+    let (shell, capture) = Capture::shell();
+    let mut config = Config::new(
+        shell,
+        root.join("cwd").join("sub"),
+        Filesystem::new(root.join("home")),
+    );
+    config.set_env(HashMap::new());
+    config.set_extra_verbose(true);
+    config.values().unwrap();
+
+    let expected = [
+        root.join("cwd").join("sub").join(".nianjia").join("config"),
+        root.join("cwd").join(".nianjia").join("config"),
+        root.join("home").join("config"),
+        root.join("home").join("credentials"),
+    ];
+    assert_eq!(config.loaded_config_files(), expected);
+    let lines = capture.contents();
+    // The credentials file may also be reported as readable by others.
+    let lines = lines
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| l.starts_with("Reading"))
+        .collect::<Vec<_>>();
+    assert_eq!(lines[0], "Reading ./.nianjia/config");
+    assert_eq!(lines[1], format!("Reading {}", expected[1].display()));
+    assert_eq!(lines.len(), 4);
+}
+
+#[test]
+fn term_verbosity_below_flags() {
+    let root = test_root("term_verbosity_below_flags");
+    let mut config = test_config(&root, "[term]\nverbose = true\nverbosity = \"quiet\"\n");
+    let settings = config.configure(0, None, &None, false, false, &None, &[], &[], None).unwrap();
+    assert_eq!(settings.verbosity, Verbosity::Quiet);
+    let settings = config.configure(1, None, &None, false, false, &None, &[], &[], None).unwrap();
+    assert_eq!(settings.verbosity, Verbosity::Verbose);
+    assert!(!config.extra_verbose());
+
+    let mut config = test_config(&root, "[term]\nverbosity = \"trace\"\n");
+    let settings = config.configure(0, None, &None, false, false, &None, &[], &[], None).unwrap();
+    assert_eq!(settings.verbosity, Verbosity::Verbose);
+    assert!(config.extra_verbose());
+
+    let mut config = test_config(&root, "[term]\nverbosity = \"loud\"\n");
+    assert!(config.configure(0, None, &None, false, false, &None, &[], &[], None).is_err());
+}
+
+#[test]
+fn config_toml_discovery() {
+    use crate::core::shell::Capture;
+
+    let root = test_root("config_toml_discovery");
+    let dir = root.join("cwd").join(".nianjia");
+    let config = test_config(&root, "");
+    fs::remove_file(dir.join("config")).unwrap();
+    fs::write(dir.join("config.toml"), "[build]\ntarget = \"toml\"\n").unwrap();
+    fs::write(root.join("home").join("config.toml"), "[build]\njobs = 2\n").unwrap();
+    assert_eq!(config.get_string("build.target").unwrap().unwrap().val, "toml");
+    assert_eq!(config.get_i64("build.jobs").unwrap().unwrap().val, 2);
+
+    fs::write(dir.join("config"), "[build]\ntarget = \"plain\"\n").unwrap();
+    let (shell, capture) = Capture::shell();
+    let mut config = Config::new(shell, root.join("cwd"), Filesystem::new(root.join("home")));
+    config.set_env(HashMap::new());
+    assert_eq!(config.get_string("build.target").unwrap().unwrap().val, "plain");
+    assert!(capture.contents().contains(
+        "both `./.nianjia/config` and `./.nianjia/config.toml` exist, using `./.nianjia/config`"
+    ));
+}
+
+#[test]
+fn malformed_credentials_error() {
+    let root = test_root("malformed_credentials_error");
+    let credentials = root.join("home").join("credentials");
+    for contents in &["[[registry]]\ntoken = \"a\"\n", "[1, 2]\n"] {
+        fs::write(&credentials, contents).unwrap();
+        let config = test_config(&root, "");
+        let err = config.values().err().unwrap();
+        assert!(
+            err.to_string().contains(&credentials.display().to_string()),
+            "{}",
+            err
+        );
+    }
+}
+
+#[test]
+fn include_shared_config() {
+    let root = test_root("include_shared_config");
+    fs::create_dir_all(root.join("shared")).unwrap();
+    fs::write(
+        root.join("shared").join("config.toml"),
+        "[build]\njobs = 3\ntarget = \"shared\"\n",
+    )
+    .unwrap();
+    let config = test_config(
+        &root,
+        "include = \"../../shared/config.toml\"\n[build]\ntarget = \"own\"\n",
+    );
+    assert_eq!(config.get_i64("build.jobs").unwrap().unwrap().val, 3);
+    assert_eq!(config.get_string("build.target").unwrap().unwrap().val, "own");
+    assert!(config.get_string("include").unwrap().is_none());
+
+    fs::write(root.join("shared").join("config.toml"), "include = \"cycle\"\n").unwrap();
+    fs::write(
+        root.join("shared").join("cycle"),
+        "include = \"../cwd/.nianjia/config\"\n",
+    )
+    .unwrap();
+    let config = test_config(&root, "include = \"../../shared/config.toml\"\n");
+    let err = config.values().err().unwrap();
+    let causes = err.iter_chain().map(|e| e.to_string()).collect::<Vec<_>>();
+    assert!(
+        causes.iter().any(|c| c.starts_with("configuration files include each other: ")
+            && c.contains("cycle` -> `./.nianjia/config`")),
+        "{:?}",
+        causes
+    );
+}
+
+#[test]
+fn get_table_entries() {
+    let root = test_root("get_table_entries");
+    let config = test_config(
+        &root,
+        "[alias]\nb = \"build\"\nr = [\"run\", \"-q\"]\n[build]\njobs = 1\n",
+    );
+    let table = config.get_table("alias").unwrap().unwrap();
+    let mut names = table.val.keys().collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, ["b", "r"]);
+    assert!(config.get_table("missing").unwrap().is_none());
+
+    let err = config.get_table("build.jobs").err().unwrap();
+    assert!(err.to_string().contains("expected a table, but found a integer"), "{}", err);
+}
+
+#[test]
+fn watch_config_changes() {
+    use std::thread;
+    use std::time::Duration;
+
+    let root = test_root("watch_config_changes");
+    let mut config = test_config(&root, "[build]\njobs = 1\n");
+    config.values().unwrap();
+    let path = root.join("cwd").join(".nianjia").join("config");
+    let mut watcher = FileWatcher::with_intervals(
+        config.loaded_config_files(),
+        Duration::from_millis(5),
+        Duration::from_millis(20),
+    );
+    assert!(!watcher.wait(Some(Duration::from_millis(20))));
+
+    let writer = {
+        let path = path.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            for jobs in 2..5 {
+                fs::write(&path, format!("[build]\njobs = {}0\n", jobs)).unwrap();
+            }
+        })
+    };
+    assert!(watcher.wait(Some(Duration::from_secs(10))));
+    writer.join().unwrap();
+    config.reload().unwrap();
+    assert_eq!(config.get_i64("build.jobs").unwrap().unwrap().val, 40);
+    assert!(!watcher.wait(Some(Duration::from_millis(20))));
+}
+
+#[test]
+fn watch_calls_back_on_edit() {
+    use std::thread;
+
+    let root = test_root("watch_calls_back_on_edit");
+    let path = root.join("cwd").join(".nianjia").join("config");
+    let mut config = test_config(&root, "[build]\njobs = 1\n");
+    // Armed once created, so the edit below can't be missed.
+    let mut watcher = config.watcher().unwrap();
+    let writer = thread::spawn(move || fs::write(&path, "[build]\njobs = 20\n").unwrap());
+
+    let mut calls = 0;
+    watcher.watch(|| {
+        calls += 1;
+        ControlFlow::Break(())
+    });
+    writer.join().unwrap();
+    assert_eq!(calls, 1);
+    config.reload().unwrap();
+    assert_eq!(config.get_i64("build.jobs").unwrap().unwrap().val, 20);
+}
+
+#[test]
+fn get_duration_and_bytes() {
+    let root = test_root("get_duration_and_bytes");
+    let config = test_config(
+        &root,
+        "[net]\ntimeout = \"30s\"\nretry-delay = 5\nbad = \"3 fortnights\"\n\
+         [cache]\nmax-size = \"500MiB\"\nmin-size = 4096\nbad = \"1XB\"\n",
+    );
+    let get_duration = |key| config.get_duration(key).unwrap().unwrap().val;
+    assert_eq!(get_duration("net.timeout"), Duration::from_secs(30));
+    assert_eq!(get_duration("net.retry-delay"), Duration::from_secs(5));
+    let get_bytes = |key| config.get_bytes(key).unwrap().unwrap().val;
+    assert_eq!(get_bytes("cache.max-size"), 500 * 1024 * 1024);
+    assert_eq!(get_bytes("cache.min-size"), 4096);
+    assert!(config.get_bytes("cache.missing").unwrap().is_none());
+
+    let err = config.get_duration("net.bad").err().unwrap();
+    assert_eq!(
+        err.iter_chain().map(|e| e.to_string()).collect::<Vec<_>>(),
+        [
+            "invalid value for `net.bad` in ./.nianjia/config".to_string(),
+            "unknown duration unit `fortnights` in `3 fortnights`, \
+             expected ms, s, m, h or d"
+                .to_string(),
+        ]
+    );
+    let err = config.get_bytes("cache.bad").err().unwrap();
+    assert!(format!("{:?}", err).contains("unknown size unit `XB` in `1XB`"));
+}
+
+#[test]
+fn config_file_from_nested_cwd() {
+    let root = test_root("config_file_from_nested_cwd");
+    test_config(&root, "[build]\ntarget = \"ancestor\"\nrustflags = [\"-g\"]\n");
+    let sub = root.join("cwd").join("sub");
+    fs::create_dir_all(sub.join(".nianjia")).unwrap();
+    fs::write(sub.join(".nianjia").join("config"), "[build]\ntarget = \"nested\"\n").unwrap();
+
+    let mut config = Config::new(
+        Shell::from_write(Box::new(io::sink())),
+        sub.clone(),
+        Filesystem::new(root.join("home")),
+    );
+    config.set_env(HashMap::new());
+    assert_eq!(config.get_string("build.target").unwrap().unwrap().val, "nested");
+
+    config
+        .set_config_files(&[PathBuf::from("../.nianjia/config")])
+        .unwrap();
+    assert_eq!(config.get_string("build.target").unwrap().unwrap().val, "ancestor");
+    assert_eq!(config.get_list("build.rustflags").unwrap().unwrap().val.len(), 1);
+    assert_eq!(config.loaded_config_files().len(), 2);
+
+    assert!(config.set_config_files(&[PathBuf::from("missing.toml")]).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn mock_executor() {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{ExitStatus, Output};
+    use std::rc::Rc;
+
+    use crate::util::process_builder::{process, ProcessBuilder};
+
+    #[derive(Debug)]
+    struct Mock(Rc<RefCell<Vec<ProcessBuilder>>>);
+
+    impl Executor for Mock {
+        fn exec(&self, cmd: &ProcessBuilder) -> NianjiaResult<Output> {
+            self.0.borrow_mut().push(cmd.clone());
+            Ok(Output {
+                status: ExitStatus::from_raw(0),
+                stdout: b"1.0.0\n".to_vec(),
+                stderr: Vec::new(),
+            })
+        }
+    }
+
+    let root = test_root("mock_executor");
+    let mut config = test_config(&root, "");
+    let ran = Rc::new(RefCell::new(Vec::new()));
+    config.set_executor(Box::new(Mock(ran.clone())));
+
+    let mut cmd = process("rustc");
+    cmd.arg("--version").cwd(&root);
+    let output = config.executor().exec(&cmd).unwrap();
+    assert_eq!(output.stdout, b"1.0.0\n");
+
+    let ran = ran.borrow();
+    assert_eq!(ran.len(), 1);
+    assert_eq!(ran[0].get_program(), "rustc");
+    assert_eq!(ran[0].get_args(), ["--version"]);
+    assert_eq!(ran[0].get_cwd(), Some(root.as_path()));
+}
+
+#[test]
+fn get_string_enum_values() {
+    let root = test_root("get_string_enum_values");
+    let config = test_config(&root, "[build]\nprofile = \"release\"\nmode = \"relase\"\n");
+    let allowed = ["dev", "release"];
+
+    let v = config.get_string_enum("build.profile", &allowed).unwrap().unwrap();
+    assert_eq!(v.val, "release");
+    assert!(config.get_string_enum("build.missing", &allowed).unwrap().is_none());
+
+    let err = config.get_string_enum("build.mode", &allowed).err().unwrap().to_string();
+    assert!(err.contains("invalid value for `build.mode`"), "{}", err);
+    assert!(err.contains("`relase`, expected one of `dev`, `release`"), "{}", err);
+    assert!(err.contains("Did you mean `release`?"), "{}", err);
+}
+
+#[test]
+fn stop_at_project_root() {
+    let root = test_root("stop_at_project_root");
+    test_config(&root, "[build]\ntarget = \"outer\"\njobs = 2\n");
+    let project = root.join("cwd").join("project");
+    fs::create_dir_all(project.join(".git")).unwrap();
+    fs::create_dir_all(project.join(".nianjia")).unwrap();
+    fs::write(project.join(".nianjia").join("config"), "[build]\ntarget = \"inner\"\n").unwrap();
+
+    let mut config = Config::new(
+        Shell::from_write(Box::new(io::sink())),
+        project.join("src"),
+        Filesystem::new(root.join("home")),
+    );
+    config.set_env(HashMap::new());
+    assert_eq!(config.get_i64("build.jobs").unwrap().unwrap().val, 2);
+
+    config.set_stop_at_project_root(true);
+    assert_eq!(config.get_string("build.target").unwrap().unwrap().val, "inner");
+    assert!(config.get_i64("build.jobs").unwrap().is_none());
+}
+
+#[test]
+fn config_warn_and_note() {
+    use crate::core::shell::Capture;
+
+    let root = test_root("config_warn_and_note");
+    let (shell, capture) = Capture::shell();
+    let config = Config::new(shell, root.join("cwd"), Filesystem::new(root.join("home")));
+    config_warn!(config, "verbosity is {:?}", config.shell().verbosity()).unwrap();
+    config_note!(config, "{} keys", 2).unwrap();
+    assert_eq!(
+        capture.contents(),
+        "warning: verbosity is Verbose\nnote: 2 keys\n"
+    );
+}
+
+#[test]
+fn get_datetime_values() {
+    let root = test_root("get_datetime_values");
+    let mut config = test_config(
+        &root,
+        "[cache]\nexpires = 2019-05-27T07:32:00Z\nday = 2019-05-27\nname = \"x\"\n",
+    );
+    let expires = config.get_datetime("cache.expires").unwrap().unwrap();
+    assert_eq!(expires.val.to_string(), "2019-05-27T07:32:00Z");
+    assert_eq!(config.get_datetime("cache.day").unwrap().unwrap().val.to_string(), "2019-05-27");
+    assert!(config.get_datetime("cache.missing").unwrap().is_none());
+    assert!(config.get_datetime("cache.name").is_err());
+    assert_eq!(
+        config.get_cv("cache.expires").unwrap().unwrap().into_toml().as_datetime().unwrap(),
+        &expires.val
+    );
+
+    let mut env = HashMap::new();
+    env.insert("NIANJIA_CACHE_EXPIRES".to_string(), "2020-01-01T00:00:00Z".to_string());
+    config.set_env(env);
+    let expires = config.get_datetime("cache.expires").unwrap().unwrap();
+    assert_eq!(expires.val.to_string(), "2020-01-01T00:00:00Z");
+}
+
+#[test]
+fn cached_reads() {
+    let root = test_root("cached_reads");
+    let mut config = test_config(&root, "[term]\ncolor = \"never\"\nverbose = true\n");
+    assert_eq!(config.get_string("term.color").unwrap().unwrap().val, "never");
+    assert!(config.get_bool("term.verbose").unwrap().unwrap().val);
+    assert!(config.get_string("term.missing").unwrap().is_none());
+    let walks = config.walks.get();
+    assert_eq!(config.get_string("term.color").unwrap().unwrap().val, "never");
+    assert!(config.get_bool("term.verbose").unwrap().unwrap().val);
+    assert!(config.get_string("term.missing").unwrap().is_none());
+    assert_eq!(config.walks.get(), walks);
+
+    let mut env = HashMap::new();
+    env.insert("NIANJIA_TERM_COLOR".to_string(), "always".to_string());
+    config.set_env(env);
+    assert_eq!(config.get_string("term.color").unwrap().unwrap().val, "always");
+    assert_eq!(config.get_string("term.color").unwrap().unwrap().val, "always");
+
+    fs::write(root.join("cwd/.nianjia/config"), "[term]\nverbose = false\n").unwrap();
+    config.reload().unwrap();
+    assert!(!config.get_bool("term.verbose").unwrap().unwrap().val);
+}
+
+#[test]
+fn table_keys_sorted() {
+    let root = test_root("table_keys_sorted");
+    fs::write(root.join("home/config"), "[alias]\nm = \"run\"\n").unwrap();
+    let config = test_config(&root, "[alias]\nz = \"test\"\nb = \"build\"\nc = \"check\"\n");
+    let table = config.get_table("alias").unwrap().unwrap();
+    assert_eq!(table.val.keys().collect::<Vec<_>>(), ["b", "c", "m", "z"]);
+    assert_eq!(config.values().unwrap().keys().collect::<Vec<_>>(), ["alias"]);
+}
+
+#[test]
+fn set_nianjia_exe() {
+    let root = test_root("set_nianjia_exe");
+    let mut config = test_config(&root, "");
+    config.set_nianjia_exe(root.join("bin/nianjia")).unwrap();
+    assert_eq!(config.nianjia_exe().unwrap(), root.join("bin/nianjia"));
+    let err = config.set_nianjia_exe(root.join("other")).err().unwrap();
+    assert!(err.to_string().contains("already set"), "{}", err);
+
+    config.clear_caches();
+    config.set_nianjia_exe(root.join("other")).unwrap();
+    assert_eq!(config.nianjia_exe().unwrap(), root.join("other"));
+}
+
+#[test]
+fn get_value_any_type() {
+    let root = test_root("get_value_any_type");
+    let config = test_config(&root, "[build]\njobs = 4\ntarget = \"x86\"\n");
+    match config.get_value("build").unwrap() {
+        Some(CV::Table(table, _)) => assert_eq!(table.len(), 2),
+        other => panic!("expected a table, got {:?}", other),
+    }
+    let jobs = config.get_value("build.jobs").unwrap().unwrap();
+    assert_eq!(jobs.desc(), "integer");
+    assert_eq!(jobs.definition_path(), root.join("cwd/.nianjia/config"));
+    assert!(config.get_value("build.missing").unwrap().is_none());
+}
+
+#[test]
+fn term_width_from_columns() {
+    let root = test_root("term_width_from_columns");
+    let mut config = test_config(&root, "");
+    assert_eq!(config.term_width(), None);
+    let mut env = HashMap::new();
+    env.insert("COLUMNS".to_string(), "132".to_string());
+    config.set_env(env);
+    assert_eq!(config.term_width(), Some(132));
+    let mut env = HashMap::new();
+    env.insert("COLUMNS".to_string(), "0".to_string());
+    config.set_env(env);
+    assert_eq!(config.term_width(), None);
+    let mut env = HashMap::new();
+    env.insert("COLUMNS".to_string(), "wide".to_string());
+    config.set_env(env);
+    assert_eq!(config.term_width(), None);
+}
+
+#[test]
+fn process_env_without_tokens() {
+    let root = test_root("process_env_without_tokens");
+    fs::write(
+        root.join("home/credentials"),
+        "[registry]\ntoken = \"secret\"\n[registries.alt]\ntoken = \"hidden\"\n",
+    )
+    .unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let private = fs::Permissions::from_mode(0o600);
+        fs::set_permissions(root.join("home/credentials"), private).unwrap();
+    }
+    let parent = test_config(
+        &root,
+        "[registry]\nindex = \"https://example.com\"\n[registries.other]\ntoken = \"shared\"\n",
+    );
+    assert_eq!(parent.credentials().unwrap().token_for("other"), Some("shared"));
+    let env = parent.process_env().unwrap();
+    assert!(env.values().all(|v| {
+        let v = v.to_str().unwrap();
+        !v.contains("secret") && !v.contains("hidden")
+    }));
+    assert!(env[RESOLVED_CONFIG_ENV].to_str().unwrap().contains("example.com"));
+
+    // A nested nianjia still finds the tokens.
+    let mut child = test_config(&root, "");
+    let mut nested = HashMap::new();
+    nested.insert("NIANJIA".to_string(), "/bin/nianjia".to_string());
+    let resolved = env[RESOLVED_CONFIG_ENV].to_str().unwrap().to_string();
+    nested.insert(RESOLVED_CONFIG_ENV.to_string(), resolved);
+    child.set_env(nested);
+    let credentials = child.credentials().unwrap();
+    assert_eq!(credentials.default_token(), Some("secret"));
+    assert_eq!(credentials.token_for("alt"), Some("hidden"));
+    assert_eq!(credentials.token_for("other"), Some("shared"));
+    assert_eq!(child.get_string("registry.index").unwrap().unwrap().val, "https://example.com");
+    assert_eq!(child.loaded_config_files(), parent.loaded_config_files());
+
+    // Reloading reads the files instead of the stale inherited values.
+    fs::write(root.join("cwd/.nianjia/config"), "[registry]\nindex = \"https://new.example\"\n")
+        .unwrap();
+    child.reload().unwrap();
+    assert_eq!(child.get_string("registry.index").unwrap().unwrap().val, "https://new.example");
+}
+
+#[test]
+fn include_expands_home_and_vars() {
+    let root = test_root("include_expands_home_and_vars");
+    fs::create_dir_all(root.join("me").join("nianjia")).unwrap();
+    fs::write(root.join("me").join("nianjia").join("shared.toml"), "[build]\njobs = 5\n").unwrap();
+    fs::write(root.join("me").join("vars.toml"), "[build]\ntarget = \"vars\"\n").unwrap();
+    fs::write(
+        root.join("me").join("nianjia").join("chain.toml"),
+        "include = \"${SHARED}/vars.toml\"\n",
+    )
+    .unwrap();
+    let mut env = HashMap::new();
+    env.insert("HOME".to_string(), root.join("me").display().to_string());
+    env.insert("SHARED".to_string(), root.join("me").display().to_string());
+
+    let mut config = test_config(&root, "include = \"~/nianjia/shared.toml\"\n");
+    config.set_env(env.clone());
+    assert_eq!(config.get_i64("build.jobs").unwrap().unwrap().val, 5);
+
+    let mut config = test_config(&root, "include = \"${HOME}/nianjia/chain.toml\"\n");
+    config.set_env(env.clone());
+    assert_eq!(config.get_string("build.target").unwrap().unwrap().val, "vars");
+
+    let mut config = test_config(&root, "include = \"~/missing.toml\"\n");
+    config.set_env(env);
+    let err = config.values().err().unwrap();
+    let causes = err.iter_chain().map(|e| e.to_string()).collect::<Vec<_>>();
+    let missing = root.join("me").join("missing.toml");
+    assert!(
+        causes.iter().any(|c| c.contains(&missing.display().to_string())),
+        "{:?}",
+        causes
+    );
+}
+
+#[test]
+fn errors_show_paths_relative_to_config_cwd() {
+    let root = test_root("relative-errors");
+    let config = test_config(&root, "[build]\njobs = \"many\"\n");
+    let err = config.get_i64("build.jobs").err().unwrap().to_string();
+    assert!(err.contains("in ./.nianjia/config"), "{}", err);
+
+    let path = root.join("cwd").join(".nianjia").join("config");
+    let definition = Definition::Path(path.clone());
+    assert_eq!(definition.display_in(&root.join("cwd")), "./.nianjia/config");
+    assert_eq!(definition.to_string(), path.display().to_string());
+}
@@ -0,0 +1,94 @@
+//! A small parser for `.env` files.
+//!
+//! Each non-empty line is `KEY=VALUE`, optionally prefixed with `export`.
+//! Lines starting with `#` are comments. Values may be wrapped in single
+//! quotes (taken literally) or double quotes (supporting `\n`, `\t`, `\"`
+//! and `\\` escapes); unquoted values end at a ` #` comment.
+
+use crate::util::errors::NianjiaResult;
+
+/// Parses the contents of a `.env` file into its assignments, in order.
+pub fn parse(contents: &str) -> NianjiaResult<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = match line.strip_prefix("export ") {
+            Some(rest) => rest.trim_start(),
+            None => line,
+        };
+        let (key, value) = match line.find('=') {
+            Some(pos) => (line[..pos].trim(), line[pos + 1..].trim()),
+            None => failure::bail!("expected `KEY=VALUE` on line {}", i + 1),
+        };
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            failure::bail!("invalid variable name `{}` on line {}", key, i + 1);
+        }
+        let value = parse_value(value).ok_or_else(|| {
+            failure::format_err!("unterminated quoted value for `{}` on line {}", key, i + 1)
+        })?;
+        vars.push((key.to_string(), value));
+    }
+    Ok(vars)
+}
+
+fn parse_value(value: &str) -> Option<String> {
+    if let Some(rest) = value.strip_prefix('\'') {
+        let end = rest.find('\'')?;
+        return Some(rest[..end].to_string());
+    }
+    if let Some(rest) = value.strip_prefix('"') {
+        let mut ret = String::new();
+        let mut chars = rest.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => return Some(ret),
+                '\\' => match chars.next()? {
+                    'n' => ret.push('\n'),
+                    't' => ret.push('\t'),
+                    other => ret.push(other),
+                },
+                c => ret.push(c),
+            }
+        }
+        return None;
+    }
+    let value = match value.find(" #") {
+        Some(pos) => &value[..pos],
+        None => value,
+    };
+    Some(value.trim_end().to_string())
+}
+
+#[test]
+fn parse_dotenv_lines() {
+    let vars = parse(
+        "# a comment\n\
+         \n\
+         PLAIN=value # trailing comment\n\
+         export EXPORTED = spaced\n\
+         SINGLE='literal \\n # kept'\n\
+         DOUBLE=\"line\\nbreak \\\"quoted\\\"\"\n\
+         EMPTY=\n",
+    )
+    .unwrap();
+    let vars = vars
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        vars,
+        [
+            ("PLAIN", "value"),
+            ("EXPORTED", "spaced"),
+            ("SINGLE", "literal \\n # kept"),
+            ("DOUBLE", "line\nbreak \"quoted\""),
+            ("EMPTY", ""),
+        ]
+    );
+
+    assert!(parse("NO_EQUALS\n").is_err());
+    assert!(parse("OPEN=\"unterminated\n").is_err());
+}
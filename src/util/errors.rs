@@ -1,10 +1,14 @@
 use std::fmt;
+use std::io;
+use std::path::Path;
 use std::str;
 use std::process::{ExitStatus, Output};
 
 use failure::{Error, Context, Fail};
 use log::trace;
 
+use crate::core::shell::diagnostic;
+
 pub type NianjiaResult<T> = failure::Fallible<T>; 
 
 pub trait NianjiaResultExt<T, E> {
@@ -90,6 +94,55 @@ impl fmt::Display for Internal {
     }
 }
 
+impl CliError {
+    /// Describes the error as a JSON diagnostic, like the errors the shell
+    /// prints, with its causes as an array, hiding the same causes as the
+    /// human-readable output does.
+    pub fn to_json(&self, verbose: bool) -> Option<serde_json::Value> {
+        let error = self.error.as_ref()?;
+        let hidden = self.unknown && !verbose;
+        let message = if hidden {
+            "An unknown error occurred".to_string()
+        } else {
+            error.to_string()
+        };
+        let (causes, _) = display_causes(error, verbose);
+        let causes = if hidden { Vec::new() } else { causes };
+        Some(diagnostic("error", &message, &causes))
+    }
+
+    /// Finds a `clap::Error` in the chain of causes, even when other errors
+    /// wrap it, such as when expanding aliases.
+    pub fn clap_error(&self) -> Option<&clap::Error> {
+        self.error
+            .as_ref()?
+            .iter_chain()
+            .find_map(|cause| cause.downcast_ref::<clap::Error>())
+    }
+
+    /// Exits like a shell would after the process behind `err`: with its exit
+    /// code, with `128 + signo` if a signal killed it, or with `default_code`
+    /// if it never ran.
+    pub fn from_process_error(err: ProcessError, default_code: i32) -> CliError {
+        let code = err.exit.and_then(status_code).unwrap_or(default_code);
+        CliError::new(err.into(), code)
+    }
+}
+
+/// Gets the messages of the causes of `error` to show to the user, and
+/// whether they are complete. Unless `verbose`, causes stop at the first one
+/// marked `Internal`.
+pub fn display_causes(error: &Error, verbose: bool) -> (Vec<String>, bool) {
+    let mut causes = Vec::new();
+    for cause in error.iter_causes() {
+        if !verbose && cause.downcast_ref::<Internal>().is_some() {
+            return (causes, false);
+        }
+        causes.push(cause.to_string());
+    }
+    (causes, true)
+}
+
 impl From<failure::Error> for CliError {
     fn from(err: failure::Error) -> CliError {
         CliError::new(err, 101)
@@ -98,11 +151,40 @@ impl From<failure::Error> for CliError {
 
 impl From<clap::Error> for CliError {
     fn from(err: clap::Error) -> CliError {
-        let code = if err.use_stderr() { 1 } else { 0 };
+        let code = clap_exit_code(&err);
         CliError::new(err.into(), code)
     }
 }
 
+fn status_code(status: ExitStatus) -> Option<i32> {
+    match exit_signal(status) {
+        Some(signal) => Some(128 + signal),
+        None => status.code(),
+    }
+}
+
+/// Gets the signal that killed the process, if any.
+#[cfg(unix)]
+fn exit_signal(status: ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(windows)]
+fn exit_signal(_status: ExitStatus) -> Option<i32> {
+    None
+}
+
+/// The code `clap::Error::exit` exits with: 0 for help and version output,
+/// which go to stdout, and 1 for usage errors.
+pub fn clap_exit_code(err: &clap::Error) -> i32 {
+    if err.use_stderr() {
+        1
+    } else {
+        0
+    }
+}
+
 // =============================================================================
 // Process errors
 #[derive(Debug, Fail)]
@@ -116,10 +198,25 @@ pub struct ProcessError {
 // =============================================================================
 // Construction helpers
 
+/// How many bytes of each of stdout and stderr `process_error` copies into
+/// the description. The complete output is still kept in `output`.
+pub const PROCESS_OUTPUT_LIMIT: usize = 4 * 1024;
+
 pub fn process_error(
     msg: &str,
     status: Option<ExitStatus>,
     output: Option<&Output>,
+) -> ProcessError {
+    process_error_with_limit(msg, status, output, PROCESS_OUTPUT_LIMIT)
+}
+
+/// Like `process_error`, but copies at most `limit` bytes of each stream into
+/// the description.
+pub fn process_error_with_limit(
+    msg: &str,
+    status: Option<ExitStatus>,
+    output: Option<&Output>,
+    limit: usize,
 ) -> ProcessError {
     let exit = match status {
         Some(s) => status_to_string(s),
@@ -128,20 +225,8 @@ pub fn process_error(
     let mut desc = format!("{} ({})", &msg, exit);
 
     if let Some(out) = output {
-        match str::from_utf8(&out.stdout) {
-            Ok(s) if !s.trim().is_empty() => {
-                desc.push_str("\n--- stdout\n");
-                desc.push_str(s);
-            }
-            Ok(..) | Err(..) => {}
-        }
-        match str::from_utf8(&out.stderr) {
-            Ok(s) if !s.trim().is_empty() => {
-                desc.push_str("\n--- stderr\n");
-                desc.push_str(s);
-            }
-            Ok(..) | Err(..) => {}
-        }
+        push_output(&mut desc, "stdout", &out.stdout, limit);
+        push_output(&mut desc, "stderr", &out.stderr, limit);
     }
 
     return ProcessError {
@@ -150,11 +235,29 @@ pub fn process_error(
         output: output.cloned(),
     };
 
+    fn push_output(desc: &mut String, name: &str, output: &[u8], limit: usize) {
+        let s = match str::from_utf8(output) {
+            Ok(s) if !s.trim().is_empty() => s,
+            Ok(..) | Err(..) => return,
+        };
+        desc.push_str("\n--- ");
+        desc.push_str(name);
+        desc.push('\n');
+        if s.len() <= limit {
+            desc.push_str(s);
+            return;
+        }
+        let mut end = limit;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        desc.push_str(&s[..end]);
+        desc.push_str("… (output truncated)");
+    }
+
     #[cfg(unix)]
     fn status_to_string(status: ExitStatus) -> String {
-        use std::os::unix::process::*;
-
-        if let Some(signal) = status.signal() {
+        if let Some(signal) = exit_signal(status) {
             let name = match signal as libc::c_int {
                 libc::SIGABRT => ", SIGABRT: process abort signal",
                 libc::SIGALRM => ", SIGALRM: alarm clock",
@@ -225,6 +328,24 @@ pub fn process_error(
     }
 }
 
+/// Describes an I/O error on `path` in plain words, instead of the raw
+/// message like "No such file or directory (os error 2)".
+pub fn friendly_io(err: &io::Error, path: &Path) -> String {
+    let path = path.display();
+    match err.kind() {
+        io::ErrorKind::NotFound => {
+            format!("`{}` or one of its parent directories does not exist", path)
+        }
+        io::ErrorKind::PermissionDenied => {
+            format!("permission denied for `{}`; check its permissions and owner", path)
+        }
+        io::ErrorKind::AlreadyExists => format!("`{}` already exists", path),
+        io::ErrorKind::InvalidData => format!("`{}` is not valid UTF-8", path),
+        io::ErrorKind::Interrupted => format!("accessing `{}` was interrupted", path),
+        _ => format!("I/O error for `{}`: {}", path, err),
+    }
+}
+
 pub fn internal<S: fmt::Display>(error: S) -> failure::Error {
     _internal(&error)
 }
@@ -232,3 +353,103 @@ pub fn internal<S: fmt::Display>(error: S) -> failure::Error {
 fn _internal(error: &dyn fmt::Display) -> failure::Error {
     Internal::new(failure::format_err!("{}", error)).into()
 }
+
+#[test]
+fn cli_error_json_causes() {
+    let error = failure::format_err!("disk full")
+        .context("could not write lock file")
+        .context("failed to build");
+    let json = CliError::new(error.into(), 101).to_json(false).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "reason": "diagnostic",
+            "level": "error",
+            "message": "failed to build",
+            "causes": ["could not write lock file", "disk full"],
+        })
+    );
+
+    let internal = Internal::new(failure::format_err!("disk full").context("write(2) failed").into());
+    let error = Error::from(internal)
+        .context("could not write lock file")
+        .context("failed to build");
+    let error = CliError::new(error.into(), 101);
+    assert_eq!(
+        error.to_json(false).unwrap()["causes"],
+        serde_json::json!(["could not write lock file"])
+    );
+    assert_eq!(
+        error.to_json(true).unwrap()["causes"],
+        serde_json::json!(["could not write lock file", "write(2) failed", "disk full"])
+    );
+}
+
+#[test]
+fn friendly_io_messages() {
+    let path = Path::new("/no/such/file");
+    let not_found = io::Error::from(io::ErrorKind::NotFound);
+    assert_eq!(
+        friendly_io(&not_found, path),
+        "`/no/such/file` or one of its parent directories does not exist"
+    );
+    let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+    assert_eq!(
+        friendly_io(&denied, path),
+        "permission denied for `/no/such/file`; check its permissions and owner"
+    );
+}
+
+#[test]
+fn nested_clap_errors() {
+    use crate::util::errors::NianjiaResultExt;
+
+    let usage =
+        clap::Error::with_description("unexpected argument", clap::ErrorKind::UnknownArgument);
+    let help = clap::Error::with_description("USAGE: nianjia", clap::ErrorKind::HelpDisplayed);
+    for (err, code) in [(usage, 1), (help, 0)] {
+        let wrapped: Result<(), _> = Err(err).chain_err(|| "failed to expand alias `b`");
+        let error = CliError::new(wrapped.unwrap_err().into(), 101);
+        let clap_err = error.clap_error().unwrap();
+        assert_eq!(clap_exit_code(clap_err), code);
+        assert_eq!(clap_err.use_stderr(), code == 1);
+    }
+    assert!(CliError::new(failure::format_err!("disk full"), 101).clap_error().is_none());
+}
+
+#[cfg(unix)]
+#[test]
+fn process_output_truncated() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let output = Output {
+        status: ExitStatus::from_raw(1 << 8),
+        stdout: "é".repeat(10_000).into_bytes(),
+        stderr: b"error: bad\n".to_vec(),
+    };
+    let err = process_error("process failed", Some(output.status), Some(&output));
+    assert!(err.desc.len() < 2 * PROCESS_OUTPUT_LIMIT);
+    assert!(err.desc.contains("é… (output truncated)\n--- stderr\nerror: bad\n"));
+    assert_eq!(err.output.unwrap().stdout.len(), 20_000);
+
+    let err = process_error_with_limit("process failed", None, Some(&output), 3);
+    assert_eq!(
+        err.desc,
+        "process failed (never executed)\n\
+         --- stdout\né… (output truncated)\n\
+         --- stderr\nerr… (output truncated)"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn process_error_exit_codes() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let exited = process_error("failed", Some(ExitStatus::from_raw(3 << 8)), None);
+    assert_eq!(CliError::from_process_error(exited, 101).exit_code, 3);
+    let killed = process_error("failed", Some(ExitStatus::from_raw(libc::SIGKILL)), None);
+    assert_eq!(CliError::from_process_error(killed, 101).exit_code, 128 + 9);
+    let never = process_error("failed", None, None);
+    assert_eq!(CliError::from_process_error(never, 101).exit_code, 101);
+}
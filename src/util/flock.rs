@@ -1,5 +1,46 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
 
+use log::warn;
+
+use crate::core::shell::Verbosity;
+use crate::util::config::Config;
+use crate::util::errors::{NianjiaResult, NianjiaResultExt};
+
+/// A locked file, unlocked when dropped.
+#[derive(Debug)]
+pub struct FileLock {
+    f: Option<File>,
+    path: PathBuf,
+}
+
+impl FileLock {
+    /// Gets the path of the locked file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Deref for FileLock {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        self.f.as_ref().unwrap()
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        if let Some(f) = self.f.take() {
+            if let Err(e) = sys::unlock(&f) {
+                warn!("failed to release lock on `{}`: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
 /// A "filesystem" is intended to be a globally shared, hence locked, resource
 /// in Nianjia.
 ///
@@ -29,6 +70,13 @@ impl Filesystem {
         self.root.push(other);
     }
 
+    /// Gets the underlying path without taking a lock.
+    ///
+    /// Like `into_path_unlocked`, this should be used with care.
+    pub fn as_path_unlocked(&self) -> &Path {
+        &self.root
+    }
+
     /// Consumes this filesystem and returns the underlying `PathBuf`.
     ///
     /// Note that this is a relatively dangerous operation and should be used
@@ -36,4 +84,221 @@ impl Filesystem {
     pub fn into_path_unlocked(self) -> PathBuf {
         self.root
     }
-}
\ No newline at end of file
+
+    /// Opens exclusive access to a file, returning the locked version of a
+    /// file.
+    ///
+    /// This function will create a file at `path` if it doesn't already exist
+    /// (including intermediate directories), and then it will acquire an
+    /// exclusive lock on `path`. If the lock is held by someone else, a
+    /// message is printed and the call blocks until it's released. `msg`
+    /// describes the locked resource.
+    pub fn open_rw<P: AsRef<Path>>(
+        &self,
+        path: P,
+        config: &Config,
+        msg: &str,
+    ) -> NianjiaResult<FileLock> {
+        let path = self.root.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .chain_err(|| format!("failed to create directory `{}`", parent.display()))?;
+        }
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .chain_err(|| format!("failed to open: {}", path.display()))?;
+        acquire(config, msg, &f, &sys::try_lock_exclusive, &sys::lock_exclusive)?;
+        Ok(FileLock { f: Some(f), path })
+    }
+
+    /// Opens shared access to a file, returning the locked version of a file.
+    ///
+    /// The file at `path` must already exist. Any number of readers may hold
+    /// the lock at once, but not while someone holds it through `open_rw`.
+    pub fn open_ro<P: AsRef<Path>>(
+        &self,
+        path: P,
+        config: &Config,
+        msg: &str,
+    ) -> NianjiaResult<FileLock> {
+        let path = self.root.join(path);
+        let f = File::open(&path).chain_err(|| format!("failed to open: {}", path.display()))?;
+        acquire(config, msg, &f, &sys::try_lock_shared, &sys::lock_shared)?;
+        Ok(FileLock { f: Some(f), path })
+    }
+}
+
+/// Takes a lock with `try_lock`, falling back to telling the user and
+/// blocking in `lock` if someone else holds it.
+fn acquire(
+    config: &Config,
+    msg: &str,
+    f: &File,
+    try_lock: &dyn Fn(&File) -> io::Result<()>,
+    lock: &dyn Fn(&File) -> io::Result<()>,
+) -> NianjiaResult<()> {
+    match try_lock(f) {
+        Ok(()) => return Ok(()),
+        Err(ref e) if sys::is_contended(e) => {}
+        Err(e) => {
+            return Err(failure::Error::from(e)
+                .context(format!("failed to lock file for {}", msg))
+                .into())
+        }
+    }
+    {
+        let mut shell = config.shell();
+        if shell.verbosity() != Verbosity::Quiet {
+            writeln!(shell.err(), "{:>12} waiting for file lock on {}", "Blocking", msg)?;
+        }
+    }
+    lock(f).chain_err(|| format!("failed to lock file for {}", msg))?;
+    Ok(())
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    pub(super) fn lock_shared(file: &File) -> io::Result<()> {
+        flock(file, libc::LOCK_SH)
+    }
+
+    pub(super) fn try_lock_shared(file: &File) -> io::Result<()> {
+        flock(file, libc::LOCK_SH | libc::LOCK_NB)
+    }
+
+    pub(super) fn lock_exclusive(file: &File) -> io::Result<()> {
+        flock(file, libc::LOCK_EX)
+    }
+
+    pub(super) fn try_lock_exclusive(file: &File) -> io::Result<()> {
+        flock(file, libc::LOCK_EX | libc::LOCK_NB)
+    }
+
+    pub(super) fn unlock(file: &File) -> io::Result<()> {
+        flock(file, libc::LOCK_UN)
+    }
+
+    pub(super) fn is_contended(err: &io::Error) -> bool {
+        err.raw_os_error() == Some(libc::EWOULDBLOCK)
+    }
+
+    fn flock(file: &File, flag: libc::c_int) -> io::Result<()> {
+        let ret = unsafe { libc::flock(file.as_raw_fd(), flag) };
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::mem;
+    use std::os::windows::io::AsRawHandle;
+
+    use winapi::shared::minwindef::DWORD;
+    use winapi::shared::winerror::ERROR_LOCK_VIOLATION;
+    use winapi::um::fileapi::{LockFileEx, UnlockFile};
+    use winapi::um::minwinbase::{LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY};
+
+    pub(super) fn lock_shared(file: &File) -> io::Result<()> {
+        lock_file(file, 0)
+    }
+
+    pub(super) fn try_lock_shared(file: &File) -> io::Result<()> {
+        lock_file(file, LOCKFILE_FAIL_IMMEDIATELY)
+    }
+
+    pub(super) fn lock_exclusive(file: &File) -> io::Result<()> {
+        lock_file(file, LOCKFILE_EXCLUSIVE_LOCK)
+    }
+
+    pub(super) fn try_lock_exclusive(file: &File) -> io::Result<()> {
+        lock_file(file, LOCKFILE_EXCLUSIVE_LOCK | LOCKFILE_FAIL_IMMEDIATELY)
+    }
+
+    pub(super) fn is_contended(err: &io::Error) -> bool {
+        err.raw_os_error() == Some(ERROR_LOCK_VIOLATION as i32)
+    }
+
+    pub(super) fn unlock(file: &File) -> io::Result<()> {
+        unsafe {
+            let ret = UnlockFile(file.as_raw_handle(), 0, 0, !0, !0);
+            if ret == 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn lock_file(file: &File, flags: DWORD) -> io::Result<()> {
+        unsafe {
+            let mut overlapped = mem::zeroed();
+            let ret = LockFileEx(file.as_raw_handle(), flags, 0, !0, !0, &mut overlapped);
+            if ret == 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
+#[test]
+fn blocking_lock_reports_waiting() {
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::core::shell::{Capture, Shell};
+
+    let root = crate::util::config::test_root("blocking_lock_reports_waiting");
+    let new_config = |shell| {
+        let mut config = Config::new(shell, root.join("cwd"), Filesystem::new(root.join("home")));
+        config.set_env(HashMap::new());
+        config
+    };
+    let fs = Filesystem::new(root.join("home"));
+
+    let (locked_tx, locked_rx) = mpsc::channel();
+    let holder = {
+        let fs = fs.clone();
+        let root = root.clone();
+        thread::spawn(move || {
+            let config = Config::new(
+                Shell::from_write(Box::new(io::sink())),
+                root.join("cwd"),
+                Filesystem::new(root.join("home")),
+            );
+            let lock = fs.open_rw("lock", &config, "test").unwrap();
+            (&*lock).write_all(b"held").unwrap();
+            locked_tx.send(()).unwrap();
+            thread::sleep(Duration::from_millis(200));
+        })
+    };
+    locked_rx.recv().unwrap();
+
+    let (shell, capture) = Capture::shell();
+    let config = new_config(shell);
+    let lock = fs.open_ro("lock", &config, "test").unwrap();
+    holder.join().unwrap();
+    assert_eq!(capture.contents(), "    Blocking waiting for file lock on test\n");
+    assert_eq!(lock.path(), root.join("home").join("lock"));
+    let mut contents = String::new();
+    (&*lock).read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "held");
+}
@@ -0,0 +1,69 @@
+//! Parsing of human-friendly durations and sizes, like `30s` or `500MiB`,
+//! see `Config::get_duration` and `Config::get_bytes`.
+
+use std::time::Duration;
+
+use crate::util::errors::NianjiaResult;
+
+/// Parses a whole number followed by `ms`, `s`, `m`, `h` or `d`. A bare
+/// number is in seconds.
+pub fn parse_duration(s: &str) -> NianjiaResult<Duration> {
+    let (n, unit) = split_number(s)?;
+    let secs: u64 = match unit {
+        "ms" => return Ok(Duration::from_millis(n)),
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => failure::bail!(
+            "unknown duration unit `{}` in `{}`, expected ms, s, m, h or d",
+            unit,
+            s
+        ),
+    };
+    match n.checked_mul(secs) {
+        Some(secs) => Ok(Duration::from_secs(secs)),
+        None => failure::bail!("duration `{}` is too large", s),
+    }
+}
+
+/// Parses a whole number followed by a decimal (`KB`, `MB`, ...) or binary
+/// (`KiB`, `MiB`, ...) unit. A bare number, or one followed by `B`, is in
+/// bytes.
+pub fn parse_bytes(s: &str) -> NianjiaResult<u64> {
+    let (n, unit) = split_number(s)?;
+    let factor: u64 = match unit {
+        "" | "B" => 1,
+        "KB" | "kB" => 1000,
+        "MB" => 1000 * 1000,
+        "GB" => 1000 * 1000 * 1000,
+        "TB" => 1000 * 1000 * 1000 * 1000,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        "TiB" => 1 << 40,
+        _ => failure::bail!(
+            "unknown size unit `{}` in `{}`, expected B, KB, MB, GB, TB, KiB, MiB, GiB or TiB",
+            unit,
+            s
+        ),
+    };
+    match n.checked_mul(factor) {
+        Some(bytes) => Ok(bytes),
+        None => failure::bail!("size `{}` is too large", s),
+    }
+}
+
+/// Splits `s` into its leading number and the unit after it.
+fn split_number(s: &str) -> NianjiaResult<(u64, &str)> {
+    let s = s.trim();
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    if end == 0 {
+        failure::bail!("expected a number at the start of `{}`", s);
+    }
+    let n = match s[..end].parse() {
+        Ok(n) => n,
+        Err(_) => failure::bail!("number in `{}` is too large", s),
+    };
+    Ok((n, s[end..].trim_start()))
+}
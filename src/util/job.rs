@@ -14,13 +14,38 @@
 //! Conveniently whenever a process in the job object spawns a new process the
 //! child will be associated with the job object as well. This means if we add
 //! ourselves to the job object we create then everything will get torn down!
+//!
+//! This module also hands out the GNU make jobserver, which limits how many
+//! processes nianjia and a surrounding `make -jN` run at once: `Client`
+//! grants an `Acquired` token per job, returned when the token is dropped.
+
+use std::io;
 
 pub use self::imp::Setup;
+pub use jobserver::{Acquired, Client};
 
 pub fn setup() -> Option<Setup> {
     unsafe { imp::setup() }
 }
 
+/// Gets the jobserver passed down by a parent process through `MAKEFLAGS`
+/// (`--jobserver-auth`), if any.
+///
+/// # Safety
+///
+/// The jobserver's file descriptors are taken from the environment, so this
+/// must be called early in `main`, before anything else may have reused
+/// them. It must be called at most once.
+pub unsafe fn client_from_env() -> Option<Client> {
+    Client::from_env()
+}
+
+/// Creates a fresh jobserver with `limit` tokens, for nianjia's own jobs and
+/// the children it is passed to, see `ProcessBuilder::inherit_jobserver`.
+pub fn new_client(limit: usize) -> io::Result<Client> {
+    Client::new(limit)
+}
+
 #[cfg(unix)]
 mod imp {
     use libc;
@@ -140,3 +165,28 @@ mod imp {
         }
     }
 }
+
+#[test]
+fn jobserver_limits_tokens() {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    let client = new_client(2).unwrap();
+    let first = client.acquire().unwrap();
+    let _second = client.acquire().unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    let waiter = {
+        let client = client.clone();
+        thread::spawn(move || {
+            let token = client.acquire().unwrap();
+            tx.send(()).unwrap();
+            drop(token);
+        })
+    };
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    drop(first);
+    rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    waiter.join().unwrap();
+}
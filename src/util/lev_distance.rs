@@ -33,6 +33,19 @@ pub fn lev_distance(me: &str, t: &str) -> usize {
     dcol[t_last + 1]
 }
 
+/// Picks the candidate nearest to `choice`, if any is near enough to be
+/// worth suggesting.
+pub fn closest<T: AsRef<str>>(choice: &str, candidates: impl IntoIterator<Item = T>) -> Option<T> {
+    // Only consider candidates with a lev_distance of 3 or less so we don't
+    // suggest out-of-the-blue options.
+    candidates
+        .into_iter()
+        .map(|c| (lev_distance(c.as_ref(), choice), c))
+        .filter(|&(d, _)| d < 4)
+        .min_by_key(|a| a.0)
+        .map(|slot| slot.1)
+}
+
 #[test]
 fn test_lev_distance() {
     use std::char::{from_u32, MAX};
@@ -1,11 +1,15 @@
 pub mod config;
+pub mod dotenv;
 pub mod errors;
 pub mod job;
 pub mod command_prelude;
 pub mod home;
+pub mod human;
 pub mod flock;
 pub mod toml;
 pub mod paths;
+pub mod progress;
 pub mod lev_distance;
 pub mod process_builder;
-pub mod read2;
\ No newline at end of file
+pub mod read2;
+pub mod watch;
\ No newline at end of file
@@ -1,8 +1,12 @@
 use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
 use std::iter;
 use std::path::{Path, PathBuf};
+use std::process;
 
-use crate::util::errors::NianjiaResult;
+use crate::util::errors::{friendly_io, NianjiaResult, NianjiaResultExt};
 
 pub fn ancestors(path: &Path) -> PathAncestors<'_> {
     PathAncestors::new(path)
@@ -67,4 +71,244 @@ pub fn resolve_executable(exec: &Path) -> NianjiaResult<PathBuf> {
     } else {
         Ok(exec.canonicalize()?)
     }
-}
\ No newline at end of file
+}
+
+/// Strips `base` from the front of `path`, or returns `path` unchanged if it
+/// isn't under `base`.
+pub fn strip_prefix_or_self<'a>(path: &'a Path, base: &Path) -> &'a Path {
+    path.strip_prefix(base).unwrap_or(path)
+}
+
+/// Renders `path` relative to `cwd` (as `./dir/file`) when it lies under
+/// `cwd`, and as given otherwise.
+pub fn display_relative(path: &Path, cwd: &Path) -> String {
+    let rel = strip_prefix_or_self(path, cwd);
+    if rel == path {
+        path.display().to_string()
+    } else {
+        Path::new(".").join(rel).display().to_string()
+    }
+}
+
+/// The permissions given to files and directories nianjia creates, see
+/// `Config::file_modes`. Ignored on Windows.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileModes {
+    pub file: u32,
+    pub dir: u32,
+}
+
+/// Reads the UTF-8 file at `path`.
+pub fn read<P: AsRef<Path>>(path: P) -> NianjiaResult<String> {
+    let path = path.as_ref();
+    fs::read_to_string(path)
+        .map_err(|e| failure::format_err!("{}", friendly_io(&e, path)))
+        .chain_err(|| format!("failed to read `{}`", path.display()))
+        .map_err(|e| e.into())
+}
+
+/// Writes `contents` to `path`, giving it `modes.file` permissions.
+pub fn write<P: AsRef<Path>, C: AsRef<[u8]>>(
+    path: P,
+    contents: C,
+    modes: FileModes,
+) -> NianjiaResult<()> {
+    write_new(path.as_ref(), contents.as_ref(), modes, false)
+}
+
+/// Writes `contents` to `path`, which must not exist yet if `create_new`.
+///
+/// The file is created with `modes.file`, and the mode of an existing file
+/// is set before anything is written, so the contents are never readable
+/// with looser permissions.
+fn write_new(
+    path: &Path,
+    contents: &[u8],
+    modes: FileModes,
+    create_new: bool,
+) -> NianjiaResult<()> {
+    let write = || -> io::Result<()> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true);
+        if create_new {
+            options.create_new(true);
+        } else {
+            options.create(true).truncate(true);
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(modes.file);
+        }
+        let mut file = options.open(path)?;
+        // The umask may have cleared bits of the requested mode.
+        set_mode(&file, modes.file)?;
+        file.write_all(contents)
+    };
+    write()
+        .map_err(|e| failure::format_err!("{}", friendly_io(&e, path)))
+        .chain_err(|| format!("failed to write `{}`", path.display()))?;
+    Ok(())
+}
+
+/// Writes `contents` to `path` through a temporary file in the same
+/// directory, so readers never observe a partially written file.
+pub fn write_atomic<P: AsRef<Path>, C: AsRef<[u8]>>(
+    path: P,
+    contents: C,
+    modes: FileModes,
+) -> NianjiaResult<()> {
+    let path = path.as_ref();
+    let tmp = append_extension(path, &format!("{}.tmp", process::id()));
+    // Left behind by an earlier process with the same id.
+    drop(fs::remove_file(&tmp));
+    write_new(&tmp, contents.as_ref(), modes, true)?;
+    fs::rename(&tmp, path).chain_err(|| {
+        drop(fs::remove_file(&tmp));
+        format!("failed to replace `{}`", path.display())
+    })?;
+    Ok(())
+}
+
+/// Creates `path` and any missing parents with `modes.dir` permissions.
+pub fn create_dir_all<P: AsRef<Path>>(path: P, modes: FileModes) -> NianjiaResult<()> {
+    let path = path.as_ref();
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(modes.dir);
+    }
+    #[cfg(not(unix))]
+    let _ = modes;
+    builder
+        .create(path)
+        .chain_err(|| format!("failed to create directory `{}`", path.display()))?;
+    Ok(())
+}
+
+/// Makes sure `path` is a directory, creating it and its parents with
+/// `modes.dir` permissions if needed.
+///
+/// Unlike `fs::create_dir_all`, a file in the way is reported as such.
+pub fn ensure_dir<P: AsRef<Path>>(path: P, modes: FileModes) -> NianjiaResult<()> {
+    let path = path.as_ref();
+    match fs::metadata(path) {
+        Ok(ref meta) if meta.is_dir() => Ok(()),
+        Ok(_) => failure::bail!(
+            "cannot create directory `{}` because a file already exists there",
+            path.display()
+        ),
+        Err(_) => create_dir_all(path, modes),
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(file: &fs::File, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    file.set_permissions(fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_file: &fs::File, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Appends `.ext` to `path`, keeping any extension it already has.
+///
+/// Unlike `Path::with_extension`, `foo.tar` becomes `foo.tar.zst` rather than
+/// `foo.zst`.
+pub fn append_extension<P: AsRef<Path>>(path: P, ext: &str) -> PathBuf {
+    let mut name: OsString = path.as_ref().as_os_str().to_os_string();
+    name.push(".");
+    name.push(ext);
+    PathBuf::from(name)
+}
+
+/// Returns the file name of an executable called `stem` on this platform.
+pub fn exe_name(stem: &str) -> String {
+    format!("{}{}", stem, env::consts::EXE_SUFFIX)
+}
+
+#[test]
+fn append_extension_keeps_existing() {
+    assert_eq!(append_extension("foo", "tar"), PathBuf::from("foo.tar"));
+    assert_eq!(append_extension("out/foo.tar", "zst"), PathBuf::from("out/foo.tar.zst"));
+}
+
+#[cfg(unix)]
+#[test]
+fn exe_name_unix() {
+    assert_eq!(exe_name("nianjia-foo"), "nianjia-foo");
+}
+
+#[cfg(windows)]
+#[test]
+fn exe_name_windows() {
+    assert_eq!(exe_name("nianjia-foo"), "nianjia-foo.exe");
+    assert_eq!(append_extension(r"out\foo.tar", "zst"), PathBuf::from(r"out\foo.tar.zst"));
+}
+
+#[cfg(unix)]
+#[test]
+fn display_relative_paths() {
+    let cwd = Path::new("/work/project");
+    assert_eq!(
+        display_relative(Path::new("/work/project/.nianjia/config"), cwd),
+        "./.nianjia/config"
+    );
+    assert_eq!(display_relative(Path::new("/work/other/config"), cwd), "/work/other/config");
+    assert_eq!(display_relative(Path::new("/etc/config"), cwd), "/etc/config");
+    assert_eq!(
+        strip_prefix_or_self(Path::new("/etc/config"), cwd),
+        Path::new("/etc/config")
+    );
+}
+
+#[test]
+fn ensure_dir_cases() {
+    let root = env::temp_dir().join(format!("nianjia-ensure-dir-{}", process::id()));
+    drop(fs::remove_dir_all(&root));
+
+    let modes = FileModes { file: 0o600, dir: 0o700 };
+    let dir = root.join("a").join("b");
+    ensure_dir(&dir, modes).unwrap();
+    assert!(dir.is_dir());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+    }
+    ensure_dir(&dir, modes).unwrap();
+
+    let file = root.join("file");
+    fs::write(&file, "").unwrap();
+    let err = ensure_dir(&file, modes).unwrap_err();
+    assert!(err.to_string().contains("because a file already exists there"));
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn write_sets_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = env::temp_dir().join(format!("nianjia-write-mode-{}", process::id()));
+    drop(fs::remove_dir_all(&root));
+    fs::create_dir_all(&root).unwrap();
+    let modes = FileModes { file: 0o600, dir: 0o700 };
+    let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+    let path = root.join("credentials");
+    fs::write(&path, "old").unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+    write(&path, "new", modes).unwrap();
+    assert_eq!(mode(&path), 0o600);
+    assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+
+    write_atomic(&path, "newer", FileModes { file: 0o640, dir: 0o750 }).unwrap();
+    assert_eq!(mode(&path), 0o640);
+    assert_eq!(fs::read_to_string(&path).unwrap(), "newer");
+    fs::remove_dir_all(&root).unwrap();
+}
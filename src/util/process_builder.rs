@@ -1,15 +1,20 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::env;
 use std::ffi::{OsStr, OsString};
 use std::fmt;
+use std::fs;
+use std::io::{self, Write};
 use std::path::Path;
-use std::process::{Command, Output, Stdio};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::str;
 
 use failure::Fail;
 use jobserver::Client;
 use shell_escape::escape;
 
-use crate::util::read2::read2;
+use crate::util::config::Config;
+use crate::util::read2::{read2, LineBuffer};
 use crate::util::errors::{process_error, NianjiaResult, NianjiaResultExt};
 
 /// A builder object for an external process, similar to `std::process::Command`.
@@ -30,6 +35,37 @@ pub struct ProcessBuilder {
     jobserver: Option<Client>,
     /// `true` to include environment variable in display.
     display_env_vars: bool,
+    /// Where the process reads its input from, if not the default.
+    stdin: Option<StdioMode>,
+    /// Where the process writes its output to, if not the default.
+    stdout: Option<StdioMode>,
+    /// Where the process writes its errors to, if not the default.
+    stderr: Option<StdioMode>,
+}
+
+/// What to connect a standard stream of a process to.
+///
+/// By default `exec` inherits all streams, `exec_with_output` pipes stdout
+/// and stderr, and `exec_with_streaming` pipes stdout and stderr and
+/// connects stdin to the null device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StdioMode {
+    /// Share the stream with nianjia.
+    Inherit,
+    /// Connect the stream to the null device.
+    Null,
+    /// Connect the stream to a pipe read or written by nianjia.
+    Piped,
+}
+
+impl StdioMode {
+    fn to_stdio(self) -> Stdio {
+        match self {
+            StdioMode::Inherit => Stdio::inherit(),
+            StdioMode::Null => Stdio::null(),
+            StdioMode::Piped => Stdio::piped(),
+        }
+    }
 }
 
 impl fmt::Display for ProcessBuilder {
@@ -60,6 +96,11 @@ impl fmt::Display for ProcessBuilder {
 }
 
 impl ProcessBuilder {
+    /// Creates a builder for running `program`, like `process`.
+    pub fn new<T: AsRef<OsStr>>(program: T) -> ProcessBuilder {
+        process(program)
+    }
+
     /// (chainable) Sets the executable for the process.
     pub fn program<T: AsRef<OsStr>>(&mut self, program: T) -> &mut ProcessBuilder {
         self.program = program.as_ref().to_os_string();
@@ -98,12 +139,39 @@ impl ProcessBuilder {
         self
     }
 
+    /// (chainable) Sets every variable in `env`, such as the environment
+    /// computed by `Config::process_env`.
+    pub fn inherit_env(&mut self, env: &HashMap<String, OsString>) -> &mut ProcessBuilder {
+        for (key, val) in env {
+            self.env.insert(key.clone(), Some(val.clone()));
+        }
+        self
+    }
+
     /// (chainable) Unsets an environment variable for the process.
     pub fn env_remove(&mut self, key: &str) -> &mut ProcessBuilder {
         self.env.insert(key.to_string(), None);
         self
     }
 
+    /// (chainable) Sets what the process reads its input from.
+    pub fn stdin(&mut self, mode: StdioMode) -> &mut ProcessBuilder {
+        self.stdin = Some(mode);
+        self
+    }
+
+    /// (chainable) Sets where the process writes its output to.
+    pub fn stdout(&mut self, mode: StdioMode) -> &mut ProcessBuilder {
+        self.stdout = Some(mode);
+        self
+    }
+
+    /// (chainable) Sets where the process writes its errors to.
+    pub fn stderr(&mut self, mode: StdioMode) -> &mut ProcessBuilder {
+        self.stderr = Some(mode);
+        self
+    }
+
     /// Gets the executable name.
     pub fn get_program(&self) -> &OsString {
         &self.program
@@ -150,8 +218,34 @@ impl ProcessBuilder {
         self
     }
 
+    /// Checks that the working directory, if set, is an existing directory,
+    /// which the OS would otherwise report as a missing program.
+    fn check_cwd(&self) -> NianjiaResult<()> {
+        let cwd = match self.get_cwd() {
+            Some(cwd) => cwd,
+            None => return Ok(()),
+        };
+        let problem = match fs::metadata(cwd) {
+            Ok(meta) if meta.is_dir() => return Ok(()),
+            Ok(_) => "is not a directory",
+            Err(_) => "does not exist",
+        };
+        Err(process_error(
+            &format!(
+                "could not execute process {}: working directory `{}` {}",
+                self,
+                cwd.display(),
+                problem
+            ),
+            None,
+            None,
+        )
+        .into())
+    }
+
     /// Runs the process, waiting for completion, and mapping non-success exit codes to an error.
     pub fn exec(&self) -> NianjiaResult<()> {
+        self.check_cwd()?;
         let mut command = self.build_command();
         let exit = command.status().chain_err(|| {
             process_error(&format!("could not execute process {}", self), None, None)
@@ -169,6 +263,27 @@ impl ProcessBuilder {
         }
     }
 
+    /// Like `exec`, but when `config` is in dry-run mode only prints
+    /// `Would run: <command line>` and succeeds without starting the process.
+    pub fn exec_checked(&self, config: &Config) -> NianjiaResult<()> {
+        if config.dry_run() {
+            writeln!(config.shell().err(), "Would run: {}", self)?;
+            return Ok(());
+        }
+        self.exec()
+    }
+
+    /// Runs the process, waiting for completion, and returns its exit status
+    /// whether or not it succeeded. Only failing to start it is an error.
+    pub fn status(&self) -> NianjiaResult<ExitStatus> {
+        self.check_cwd()?;
+        let mut command = self.build_command();
+        let status = command.status().chain_err(|| {
+            process_error(&format!("could not execute process {}", self), None, None)
+        })?;
+        Ok(status)
+    }
+
     /// Replaces the current process with the target process.
     ///
     /// On Unix, this executes the process using the Unix syscall `execvp`, which will block
@@ -190,6 +305,7 @@ impl ProcessBuilder {
 
     /// Executes the process, returning the stdio output, or an error if non-zero exit status.
     pub fn exec_with_output(&self) -> NianjiaResult<Output> {
+        self.check_cwd()?;
         let mut command = self.build_command();
 
         let output = command.output().chain_err(|| {
@@ -208,68 +324,93 @@ impl ProcessBuilder {
         }
     }
 
+    /// Like `exec_with_output`, but wraps the output in a `CommandOutput`.
+    pub fn exec_captured(&self) -> NianjiaResult<CommandOutput> {
+        let output = self.exec_with_output()?;
+        Ok(CommandOutput {
+            command: self.to_string(),
+            output,
+        })
+    }
+
+    /// Executes the process and returns its stdout with surrounding
+    /// whitespace trimmed, or an error if it exits unsuccessfully or its
+    /// stdout is not valid UTF-8.
+    pub fn exec_stdout_string(&self) -> NianjiaResult<String> {
+        Ok(self.exec_captured()?.stdout_str()?.trim().to_string())
+    }
+
     /// Executes a command, passing each line of stdout and stderr to the supplied callbacks, which
     /// can mutate the string data.
     ///
     /// If any invocations of these function return an error, it will be propagated.
     ///
     /// Optionally, output can be passed to errors using `print_output`
+    ///
+    /// A stream set to `StdioMode::Inherit` is copied to nianjia's own stream
+    /// and one set to `StdioMode::Null` is discarded; neither is passed to
+    /// the callbacks.
     pub fn exec_with_streaming(
         &self,
         on_stdout_line: &mut dyn FnMut(&str) -> NianjiaResult<()>,
         on_stderr_line: &mut dyn FnMut(&str) -> NianjiaResult<()>,
         capture_output: bool,
     ) -> NianjiaResult<Output> {
+        self.check_cwd()?;
         let mut stdout = Vec::new();
         let mut stderr = Vec::new();
 
         let mut cmd = self.build_command();
         cmd.stdout(Stdio::piped())
             .stderr(Stdio::piped())
-            .stdin(Stdio::null());
+            .stdin(self.stdin.unwrap_or(StdioMode::Null).to_stdio());
+        let out_mode = self.stdout.unwrap_or(StdioMode::Piped);
+        let err_mode = self.stderr.unwrap_or(StdioMode::Piped);
 
         let mut callback_error = None;
         let status = (|| {
             let mut child = cmd.spawn()?;
             let out = child.stdout.take().unwrap();
             let err = child.stderr.take().unwrap();
+            let mut out_lines = LineBuffer::new();
+            let mut err_lines = LineBuffer::new();
             read2(out, err, &mut |is_out, data, eof| {
-                let idx = if eof {
-                    data.len()
-                } else {
-                    match data.iter().rposition(|b| *b == b'\n') {
-                        Some(i) => i + 1,
-                        None => return,
+                match if is_out { out_mode } else { err_mode } {
+                    StdioMode::Piped => {}
+                    StdioMode::Null => return data.clear(),
+                    StdioMode::Inherit => {
+                        // Failing to echo the child's output shouldn't fail it.
+                        drop(if is_out {
+                            io::stdout().write_all(data)
+                        } else {
+                            io::stderr().write_all(data)
+                        });
+                        return data.clear();
                     }
-                };
-                {
-                    // scope for new_lines
-                    let new_lines = if capture_output {
-                        let dst = if is_out { &mut stdout } else { &mut stderr };
-                        let start = dst.len();
-                        let data = data.drain(..idx);
-                        dst.extend(data);
-                        &dst[start..]
+                }
+                if capture_output {
+                    let dst = if is_out { &mut stdout } else { &mut stderr };
+                    dst.extend_from_slice(data);
+                }
+                let lines = if is_out { &mut out_lines } else { &mut err_lines };
+                let mut on_line = |line: &str| {
+                    if callback_error.is_some() {
+                        return;
+                    }
+                    let callback_result = if is_out {
+                        on_stdout_line(line)
                     } else {
-                        &data[..idx]
+                        on_stderr_line(line)
                     };
-                    for line in String::from_utf8_lossy(new_lines).lines() {
-                        if callback_error.is_some() {
-                            break;
-                        }
-                        let callback_result = if is_out {
-                            on_stdout_line(line)
-                        } else {
-                            on_stderr_line(line)
-                        };
-                        if let Err(e) = callback_result {
-                            callback_error = Some(e);
-                        }
+                    if let Err(e) = callback_result {
+                        callback_error = Some(e);
                     }
+                };
+                lines.push(data, &mut on_line);
+                if eof {
+                    lines.finish(&mut on_line);
                 }
-                if !capture_output {
-                    data.drain(..idx);
-                }
+                data.clear();
             })?;
             child.wait()
         })()
@@ -302,6 +443,73 @@ impl ProcessBuilder {
         Ok(output)
     }
 
+    /// Renders the program and arguments as a POSIX shell command line, which
+    /// `from_command_line` parses back into the same program and arguments.
+    pub fn to_shell_string(&self) -> String {
+        // POSIX quoting even on Windows, to match `from_command_line`.
+        let mut line = shell_escape::unix::escape(self.program.to_string_lossy()).into_owned();
+        for arg in &self.args {
+            line.push(' ');
+            line.push_str(&shell_escape::unix::escape(arg.to_string_lossy()));
+        }
+        line
+    }
+
+    /// Parses a command line as written by `to_shell_string`.
+    ///
+    /// Words are split on whitespace. Single quotes preserve everything up to
+    /// the closing quote, while within double quotes and unquoted words a
+    /// backslash escapes the next character.
+    pub fn from_command_line(line: &str) -> NianjiaResult<ProcessBuilder> {
+        let mut words = Vec::new();
+        let mut word: Option<String> = None;
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                c if c.is_whitespace() => words.extend(word.take()),
+                '\'' => {
+                    let word = word.get_or_insert_with(String::new);
+                    loop {
+                        match chars.next() {
+                            Some('\'') => break,
+                            Some(c) => word.push(c),
+                            None => failure::bail!("unterminated `'` in `{}`", line),
+                        }
+                    }
+                }
+                '"' => {
+                    let word = word.get_or_insert_with(String::new);
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => match chars.next() {
+                                Some(c) => word.push(c),
+                                None => failure::bail!("unterminated `\"` in `{}`", line),
+                            },
+                            Some(c) => word.push(c),
+                            None => failure::bail!("unterminated `\"` in `{}`", line),
+                        }
+                    }
+                }
+                '\\' => match chars.next() {
+                    Some(c) => word.get_or_insert_with(String::new).push(c),
+                    None => failure::bail!("trailing `\\` in `{}`", line),
+                },
+                c => word.get_or_insert_with(String::new).push(c),
+            }
+        }
+        words.extend(word);
+
+        let mut words = words.into_iter();
+        let program = match words.next() {
+            Some(program) => program,
+            None => failure::bail!("empty command line"),
+        };
+        let mut cmd = process(program);
+        cmd.args(&words.collect::<Vec<_>>());
+        Ok(cmd)
+    }
+
     /// Converts `ProcessBuilder` into a `std::process::Command`, and handles the jobserver, if
     /// present.
     pub fn build_command(&self) -> Command {
@@ -322,6 +530,15 @@ impl ProcessBuilder {
                 }
             }
         }
+        if let Some(mode) = self.stdin {
+            command.stdin(mode.to_stdio());
+        }
+        if let Some(mode) = self.stdout {
+            command.stdout(mode.to_stdio());
+        }
+        if let Some(mode) = self.stderr {
+            command.stderr(mode.to_stdio());
+        }
         if let Some(ref c) = self.jobserver {
             c.configure(&mut command);
         }
@@ -329,6 +546,32 @@ impl ProcessBuilder {
     }
 }
 
+/// The output of a successfully executed process.
+#[derive(Debug)]
+pub struct CommandOutput {
+    /// The command line, for error messages.
+    command: String,
+    output: Output,
+}
+
+impl CommandOutput {
+    /// Gets stdout as a string, or an error if it isn't valid UTF-8.
+    pub fn stdout_str(&self) -> NianjiaResult<&str> {
+        str::from_utf8(&self.output.stdout)
+            .chain_err(|| format!("process {} produced invalid UTF-8 on stdout", self.command))
+            .map_err(|e| e.into())
+    }
+
+    /// Gets stderr as a string, replacing invalid UTF-8.
+    pub fn stderr_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.output.stderr)
+    }
+
+    pub fn into_output(self) -> Output {
+        self.output
+    }
+}
+
 /// A helper function to create a `ProcessBuilder`.
 pub fn process<T: AsRef<OsStr>>(cmd: T) -> ProcessBuilder {
     ProcessBuilder {
@@ -338,6 +581,30 @@ pub fn process<T: AsRef<OsStr>>(cmd: T) -> ProcessBuilder {
         env: HashMap::new(),
         jobserver: None,
         display_env_vars: false,
+        stdin: None,
+        stdout: None,
+        stderr: None,
+    }
+}
+
+/// Runs processes on behalf of commands, see `Config::executor`.
+///
+/// Commands go through this rather than calling `exec_with_output`
+/// themselves, so that tests can check the exact `ProcessBuilder` and return
+/// canned output instead of spawning anything.
+pub trait Executor: fmt::Debug {
+    /// Runs `cmd` to completion, returning its output, or an error if it
+    /// can't be run or exits unsuccessfully.
+    fn exec(&self, cmd: &ProcessBuilder) -> NianjiaResult<Output>;
+}
+
+/// The `Executor` that actually runs processes.
+#[derive(Debug, Default)]
+pub struct ProcessExecutor;
+
+impl Executor for ProcessExecutor {
+    fn exec(&self, cmd: &ProcessBuilder) -> NianjiaResult<Output> {
+        cmd.exec_with_output()
     }
 }
 
@@ -348,6 +615,7 @@ mod imp {
     use super::ProcessBuilder;
 
     pub fn exec_replace(process_builder: &ProcessBuilder) -> NianjiaResult<()> {
+        process_builder.check_cwd()?;
         let mut command = process_builder.build_command();
         let error = command.exec();
         Err(failure::Error::from(error)
@@ -383,3 +651,136 @@ mod imp {
         process_builder.exec()
     }
 }
+
+#[cfg(unix)]
+#[test]
+fn exec_stdout_string() {
+    use crate::util::errors::ProcessError;
+
+    let out = process("sh").args(&["-c", "echo '  hello  '"]).exec_stdout_string();
+    assert_eq!(out.unwrap(), "hello");
+
+    let err = process("sh").args(&["-c", "echo oops; exit 3"]).exec_stdout_string().unwrap_err();
+    let perr = err.downcast_ref::<ProcessError>().unwrap();
+    assert_eq!(perr.exit.and_then(|s| s.code()), Some(3));
+    assert!(perr.desc.contains("oops"));
+
+    let err = process("sh").args(&["-c", "printf '\\377'"]).exec_stdout_string().unwrap_err();
+    assert!(err.to_string().contains("invalid UTF-8 on stdout"));
+}
+
+#[cfg(unix)]
+#[test]
+fn run_processes() {
+    let mut cmd = ProcessBuilder::new("echo");
+    cmd.args(&["hello", "two words"]).cwd("/");
+    assert_eq!(cmd.to_string(), "`echo hello 'two words'`");
+    assert_eq!(cmd.exec_with_output().unwrap().stdout, b"hello two words\n");
+
+    let status = ProcessBuilder::new("sh").args(&["-c", "exit 4"]).status().unwrap();
+    assert_eq!(status.code(), Some(4));
+    assert!(ProcessBuilder::new("/no/such/program").status().is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn stdio_modes() {
+    let mut cmd = process("sh");
+    cmd.args(&["-c", "echo out; echo err >&2"]);
+    let output = cmd.exec_with_output().unwrap();
+    assert_eq!(output.stdout, b"out\n");
+
+    let output = cmd.clone().stdout(StdioMode::Null).exec_with_output().unwrap();
+    assert!(output.stdout.is_empty());
+    assert_eq!(output.stderr, b"err\n");
+
+    let mut lines = Vec::new();
+    cmd.stdout(StdioMode::Piped)
+        .stderr(StdioMode::Null)
+        .exec_with_streaming(
+            &mut |line| {
+                lines.push(line.to_string());
+                Ok(())
+            },
+            &mut |line| panic!("unexpected stderr {}", line),
+            true,
+        )
+        .unwrap();
+    assert_eq!(lines, ["out"]);
+}
+
+#[test]
+fn shell_string_round_trip() {
+    let args = [
+        "plain",
+        "with space",
+        "it's",
+        "bang!",
+        "\"double\"",
+        "",
+        "back\\slash",
+        "$HOME `x` *",
+        "tab\tnewline\n",
+    ];
+    let mut cmd = process("my prog");
+    cmd.args(&args);
+    let line = cmd.to_shell_string();
+    assert!(line.starts_with("'my prog' plain 'with space' 'it'\\''s'"));
+
+    let parsed = ProcessBuilder::from_command_line(&line).unwrap();
+    assert_eq!(parsed.get_program(), cmd.get_program());
+    assert_eq!(parsed.get_args(), cmd.get_args());
+
+    let parsed = ProcessBuilder::from_command_line(r#"run "a \"b\"" c\ d ''"#).unwrap();
+    assert_eq!(parsed.get_args(), ["a \"b\"", "c d", ""]);
+    assert!(ProcessBuilder::from_command_line("run 'open").is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn missing_cwd() {
+    let missing = env::temp_dir().join("nianjia-tests").join("missing-cwd");
+    let mut cmd = process("true");
+    cmd.cwd(&missing);
+    let err = cmd.exec().unwrap_err();
+    let perr = err.downcast_ref::<crate::util::errors::ProcessError>().unwrap();
+    assert_eq!(
+        perr.desc,
+        format!(
+            "could not execute process `true`: working directory `{}` does not exist \
+             (never executed)",
+            missing.display()
+        )
+    );
+    assert!(cmd.exec_with_output().is_err());
+
+    cmd.cwd(env::current_exe().unwrap());
+    let err = cmd.status().unwrap_err().to_string();
+    assert!(err.ends_with("is not a directory (never executed)"), "{}", err);
+}
+
+#[cfg(unix)]
+#[test]
+fn exec_checked_dry_run() {
+    use std::collections::HashMap;
+
+    use crate::core::shell::Capture;
+    use crate::util::flock::Filesystem;
+
+    let root = crate::util::config::test_root("exec_checked_dry_run");
+    let (shell, capture) = Capture::shell();
+    let mut config = Config::new(shell, root.join("cwd"), Filesystem::new(root.join("home")));
+    config.set_env(HashMap::new());
+    let marker = root.join("ran");
+    let mut cmd = process("sh");
+    cmd.arg("-c").arg(format!("touch '{}'; exit 3", marker.display()));
+
+    config.set_dry_run(true);
+    cmd.exec_checked(&config).unwrap();
+    assert!(!marker.exists());
+    assert_eq!(capture.contents(), format!("Would run: {}\n", cmd));
+
+    config.set_dry_run(false);
+    assert!(cmd.exec_checked(&config).is_err());
+    assert!(marker.exists());
+}
@@ -0,0 +1,424 @@
+use std::time::{Duration, Instant};
+
+use crate::core::shell::Verbosity;
+use crate::util::config::Config;
+use crate::util::errors::NianjiaResult;
+
+/// A single-line progress bar drawn on stderr, like
+/// `    Building [=====>        ] 12/40`, filling the terminal's width.
+///
+/// Nothing is drawn unless stderr is a terminal and the shell isn't quiet.
+/// Redraws are throttled, and the line is cleared when dropped.
+///
+/// With `term.progress.when = "always"`, progress is also reported when
+/// stderr isn't a terminal, as one full line per redraw, which keeps CI logs
+/// readable.
+pub struct Progress<'cfg> {
+    state: Option<State<'cfg>>,
+}
+
+struct State<'cfg> {
+    config: &'cfg Config,
+    name: String,
+    throttle: Throttle,
+    /// Whether to print each update on its own line instead of in place.
+    lines: bool,
+}
+
+/// A spinner for operations without a known total, drawn on stderr as
+/// `⠋ message`, or with ASCII frames if `term.unicode` is `false`.
+///
+/// Like `Progress`, nothing is drawn unless stderr is a terminal and the
+/// shell isn't quiet, and the line is cleared when dropped.
+pub struct Spinner<'cfg> {
+    state: Option<SpinnerState<'cfg>>,
+}
+
+struct SpinnerState<'cfg> {
+    config: &'cfg Config,
+    message: String,
+    frames: &'static [&'static str],
+    frame: usize,
+    throttle: Throttle,
+}
+
+const UNICODE_FRAMES: &[&str] = &["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+const ASCII_FRAMES: &[&str] = &["|", "/", "-", "\\"];
+
+/// Limits how often progress is redrawn.
+struct Throttle {
+    first: bool,
+    last_update: Instant,
+    interval: Duration,
+}
+
+impl<'cfg> Progress<'cfg> {
+    pub fn new(name: &str, config: &'cfg Config) -> Progress<'cfg> {
+        Progress::with_throttle(name, config, Duration::from_millis(100))
+    }
+
+    /// Creates a progress indicator redrawn at most once per `interval`.
+    pub fn with_throttle(name: &str, config: &'cfg Config, interval: Duration) -> Progress<'cfg> {
+        // Ignore errors in the configuration files.
+        let always = config
+            .get_string("term.progress.when")
+            .unwrap_or(None)
+            .is_some_and(|when| when.val == "always");
+        let (tty, quiet) = {
+            let shell = config.shell();
+            (shell.supports_status_drawing(), shell.verbosity() == Verbosity::Quiet)
+        };
+        if !quiet && (tty || always) {
+            Progress::enabled(name, config, interval, !tty)
+        } else {
+            Progress { state: None }
+        }
+    }
+
+    fn enabled(name: &str, config: &'cfg Config, interval: Duration, lines: bool) -> Progress<'cfg> {
+        Progress {
+            state: Some(State {
+                config,
+                name: name.to_string(),
+                throttle: Throttle::new(interval),
+                lines,
+            }),
+        }
+    }
+
+    /// Reports that `cur` out of `max` units are done. The final state
+    /// (`cur >= max`) is always drawn.
+    pub fn tick(&mut self, cur: usize, max: usize) -> NianjiaResult<()> {
+        self.tick_at(cur, max, Instant::now())
+    }
+
+    fn tick_at(&mut self, cur: usize, max: usize, now: Instant) -> NianjiaResult<()> {
+        let state = match self.state {
+            Some(ref mut state) => state,
+            None => return Ok(()),
+        };
+        if cur < max && !state.throttle.allowed(now) {
+            return Ok(());
+        }
+        state.draw(cur, max)
+    }
+
+    /// Erases the progress line.
+    pub fn clear(&mut self) {
+        if let Some(ref state) = self.state {
+            if !state.lines {
+                state.config.shell().err_erase_line();
+            }
+        }
+    }
+}
+
+impl<'cfg> Drop for Progress<'cfg> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<'cfg> Spinner<'cfg> {
+    pub fn new(message: &str, config: &'cfg Config) -> Spinner<'cfg> {
+        Spinner::with_throttle(message, config, Duration::from_millis(100))
+    }
+
+    /// Creates a spinner advanced at most once per `interval`.
+    pub fn with_throttle(message: &str, config: &'cfg Config, interval: Duration) -> Spinner<'cfg> {
+        if config.shell().supports_status_drawing() {
+            Spinner::enabled(message, config, interval)
+        } else {
+            Spinner { state: None }
+        }
+    }
+
+    fn enabled(message: &str, config: &'cfg Config, interval: Duration) -> Spinner<'cfg> {
+        // Ignore errors in the configuration files.
+        let unicode = match config.get_bool("term.unicode") {
+            Ok(Some(unicode)) => unicode.val,
+            _ => true,
+        };
+        Spinner {
+            state: Some(SpinnerState {
+                config,
+                message: message.to_string(),
+                frames: if unicode { UNICODE_FRAMES } else { ASCII_FRAMES },
+                frame: 0,
+                throttle: Throttle::new(interval),
+            }),
+        }
+    }
+
+    /// Draws the next frame, unless the last one was drawn too recently.
+    pub fn tick(&mut self) -> NianjiaResult<()> {
+        self.tick_at(Instant::now())
+    }
+
+    fn tick_at(&mut self, now: Instant) -> NianjiaResult<()> {
+        let state = match self.state {
+            Some(ref mut state) => state,
+            None => return Ok(()),
+        };
+        if !state.throttle.allowed(now) {
+            return Ok(());
+        }
+        let mut shell = state.config.shell();
+        if !shell.supports_status_drawing() {
+            return Ok(());
+        }
+        let frame = state.frames[state.frame % state.frames.len()];
+        write!(shell.err(), "{} {}\r", frame, state.message)?;
+        shell.set_needs_clear(true);
+        state.frame += 1;
+        Ok(())
+    }
+
+    /// Erases the spinner, which isn't drawn again.
+    pub fn clear(&mut self) {
+        if let Some(state) = self.state.take() {
+            state.config.shell().err_erase_line();
+        }
+    }
+}
+
+impl<'cfg> Drop for Spinner<'cfg> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<'cfg> State<'cfg> {
+    fn draw(&mut self, cur: usize, max: usize) -> NianjiaResult<()> {
+        let width = self.config.term_width();
+        let mut shell = self.config.shell();
+        if shell.verbosity() == Verbosity::Quiet {
+            return Ok(());
+        }
+        if self.lines {
+            writeln!(shell.err(), "{}: {}/{}", self.name, cur, max)?;
+            return Ok(());
+        }
+        if !shell.supports_status_drawing() {
+            return Ok(());
+        }
+        let line = render_bar(&self.name, cur, max, width);
+        // Leave the cursor at the start of the line, so the next message
+        // overwrites it after `err_erase_line`.
+        write!(shell.err(), "{}\r", line)?;
+        shell.set_needs_clear(true);
+        Ok(())
+    }
+}
+
+/// The width assumed when the terminal's isn't known.
+const FALLBACK_WIDTH: usize = 80;
+
+/// Renders `name`, a bar and a `cur/max` counter to fit in `width` columns,
+/// leaving out the bar if there's no room for it.
+fn render_bar(name: &str, cur: usize, max: usize, width: Option<usize>) -> String {
+    let width = width.unwrap_or(FALLBACK_WIDTH);
+    let name = format!("{:>12}", name);
+    let counter = format!("{}/{}", cur, max);
+    // A space after the name, the brackets, and a space before the counter.
+    let extra = name.chars().count() + counter.len() + 4;
+    // Keep the last column free, where some terminals wrap.
+    let bar_width = width.saturating_sub(extra + 1);
+    if bar_width < 5 {
+        return format!("{} {}", name, counter);
+    }
+    let filled = bar_width * cur.min(max) / max.max(1);
+    let mut bar = "=".repeat(filled);
+    if filled < bar_width {
+        bar.push('>');
+        bar.push_str(&" ".repeat(bar_width - filled - 1));
+    }
+    format!("{} [{}] {}", name, bar, counter)
+}
+
+impl Throttle {
+    fn new(interval: Duration) -> Throttle {
+        Throttle {
+            first: true,
+            last_update: Instant::now(),
+            interval,
+        }
+    }
+
+    /// Returns whether a redraw at `now` is allowed, recording it if so.
+    fn allowed(&mut self, now: Instant) -> bool {
+        if !self.first && now.duration_since(self.last_update) < self.interval {
+            return false;
+        }
+        self.first = false;
+        self.last_update = now;
+        true
+    }
+}
+
+#[test]
+fn throttle_bounds_redraws() {
+    let start = Instant::now();
+    let mut throttle = Throttle::new(Duration::from_millis(100));
+    let draws = (0..1000)
+        .filter(|&ms| throttle.allowed(start + Duration::from_millis(ms)))
+        .count();
+    assert_eq!(draws, 10);
+}
+
+#[test]
+fn tick_redraws_bounded() {
+    use std::collections::HashMap;
+
+    use crate::core::shell::Capture;
+    use crate::util::flock::Filesystem;
+
+    let root = crate::util::config::test_root("tick_redraws_bounded");
+    let (shell, capture) = Capture::tty_shell();
+    let mut config = Config::new(shell, root.join("cwd"), Filesystem::new(root.join("home")));
+    config.set_env(HashMap::new());
+
+    let mut progress = Progress::with_throttle("Building", &config, Duration::from_secs(3600));
+    for cur in 0..=10_000 {
+        progress.tick(cur, 10_000).unwrap();
+    }
+    let contents = capture.contents();
+    let draws = contents.split('\r').filter(|line| line.contains("Building")).collect::<Vec<_>>();
+    assert_eq!(draws.len(), 2, "{:?}", contents);
+    assert!(draws[0].ends_with(" 0/10000"));
+    assert!(draws[1].ends_with(" 10000/10000"));
+}
+
+#[test]
+fn bar_width_fallback() {
+    assert_eq!(render_bar("Building", 12, 40, None), render_bar("Building", 12, 40, Some(80)));
+    assert_eq!(
+        render_bar("Building", 12, 40, Some(40)),
+        "    Building [=====>            ] 12/40"
+    );
+    assert_eq!(
+        render_bar("Building", 40, 40, Some(40)),
+        "    Building [==================] 40/40"
+    );
+    assert_eq!(render_bar("Building", 12, 40, Some(20)), "    Building 12/40");
+}
+
+#[test]
+fn progress_bar_throttled() {
+    use std::collections::HashMap;
+
+    use crate::core::shell::Capture;
+    use crate::util::flock::Filesystem;
+
+    let root = crate::util::config::test_root("progress_bar_throttled");
+    let (shell, capture) = Capture::tty_shell();
+    let mut config = Config::new(shell, root.join("cwd"), Filesystem::new(root.join("home")));
+    config.set_env(HashMap::new());
+
+    let start = Instant::now();
+    let mut progress = Progress::enabled("Building", &config, Duration::from_millis(100), false);
+    for (cur, ms) in &[(0, 0), (1, 50), (2, 99), (3, 100), (4, 150), (5, 160)] {
+        progress.tick_at(*cur, 5, start + Duration::from_millis(*ms)).unwrap();
+    }
+    let lines = capture.contents();
+    let counters = lines
+        .split('\r')
+        .filter_map(|line| line.rsplit(' ').next())
+        .filter(|counter| !counter.is_empty())
+        .collect::<Vec<_>>();
+    assert_eq!(counters, ["0/5", "3/5", "5/5"]);
+}
+
+#[test]
+fn non_tty_progress_lines() {
+    use std::collections::HashMap;
+
+    use crate::core::shell::Capture;
+    use crate::util::flock::Filesystem;
+
+    let root = crate::util::config::test_root("non_tty_progress_lines");
+    let (shell, capture) = Capture::shell();
+    let mut config = Config::new(shell, root.join("cwd"), Filesystem::new(root.join("home")));
+    let mut env = HashMap::new();
+    env.insert("NIANJIA_TERM_PROGRESS_WHEN".to_string(), "always".to_string());
+    config.set_env(env);
+
+    let mut progress = Progress::with_throttle("Progress", &config, Duration::from_secs(3600));
+    for cur in 0..=100 {
+        progress.tick(cur, 100).unwrap();
+    }
+    drop(progress);
+    assert_eq!(capture.contents(), "Progress: 0/100\nProgress: 100/100\n");
+
+    config.set_env(HashMap::new());
+    Progress::new("Progress", &config).tick(100, 100).unwrap();
+    assert_eq!(capture.contents(), "Progress: 0/100\nProgress: 100/100\n");
+}
+
+#[test]
+fn spinner_frames() {
+    use std::collections::HashMap;
+
+    use crate::core::shell::Capture;
+    use crate::util::flock::Filesystem;
+
+    let root = crate::util::config::test_root("spinner_frames");
+    let (shell, capture) = Capture::tty_shell();
+    let mut config = Config::new(shell, root.join("cwd"), Filesystem::new(root.join("home")));
+    config.set_env(HashMap::new());
+
+    config.shell().set_verbosity(Verbosity::Quiet);
+    Spinner::new("Waiting", &config).tick().unwrap();
+    assert_eq!(capture.contents(), "");
+    config.shell().set_verbosity(Verbosity::Normal);
+
+    let start = Instant::now();
+    let mut spinner = Spinner::enabled("Waiting", &config, Duration::from_millis(100));
+    for ms in &[0, 50, 100, 250] {
+        spinner.tick_at(start + Duration::from_millis(*ms)).unwrap();
+    }
+    assert_eq!(capture.contents(), "⠋ Waiting\r\x1B[K⠙ Waiting\r\x1B[K⠹ Waiting\r");
+    drop(spinner);
+    assert_eq!(capture.contents(), "⠋ Waiting\r\x1B[K⠙ Waiting\r\x1B[K⠹ Waiting\r\x1B[K");
+
+    let mut spinner = Spinner::enabled("Waiting", &config, Duration::from_millis(100));
+    spinner.clear();
+    spinner.tick_at(start + Duration::from_secs(1)).unwrap();
+    drop(spinner);
+    assert_eq!(capture.contents(), "⠋ Waiting\r\x1B[K⠙ Waiting\r\x1B[K⠹ Waiting\r\x1B[K\x1B[K");
+
+    let mut env = HashMap::new();
+    env.insert("NIANJIA_TERM_UNICODE".to_string(), "false".to_string());
+    config.set_env(env);
+    Spinner::enabled("Waiting", &config, Duration::from_millis(100)).tick().unwrap();
+    assert!(capture.contents().ends_with("\x1B[K| Waiting\r\x1B[K"));
+}
+
+#[test]
+fn no_drawing_without_terminal() {
+    use std::collections::HashMap;
+
+    use crate::core::shell::{Capture, Shell};
+    use crate::util::flock::Filesystem;
+
+    let root = crate::util::config::test_root("no_drawing_without_terminal");
+    let (shell, capture) = Capture::tty_shell();
+    let mut config = Config::new(shell, root.join("cwd"), Filesystem::new(root.join("home")));
+    config.set_env(HashMap::new());
+
+    let mut progress = Progress::new("Building", &config);
+    let mut spinner = Spinner::new("Waiting", &config);
+    config.shell().set_verbosity(Verbosity::Quiet);
+    progress.tick(1, 2).unwrap();
+    spinner.tick().unwrap();
+    assert_eq!(capture.contents(), "");
+
+    // The shell is replaced by one writing to a file after drawing started.
+    let (plain, plain_capture) = Capture::shell();
+    *config.shell() = plain;
+    progress.tick(2, 2).unwrap();
+    spinner.tick().unwrap();
+    assert_eq!(capture.contents(), "");
+    assert_eq!(plain_capture.contents(), "");
+    assert!(!Shell::from_write(Box::new(std::io::sink())).supports_status_drawing());
+}
@@ -1,5 +1,42 @@
 pub use self::imp::read2;
 
+/// Reassembles the arbitrary chunks handed out by `read2` into whole lines.
+#[derive(Debug, Default)]
+pub struct LineBuffer {
+    partial: Vec<u8>,
+}
+
+impl LineBuffer {
+    pub fn new() -> LineBuffer {
+        LineBuffer::default()
+    }
+
+    /// Appends `data`, passing each line completed by it to `on_line` without
+    /// its line terminator. An unterminated tail is kept for the next call.
+    pub fn push(&mut self, data: &[u8], on_line: &mut dyn FnMut(&str)) {
+        self.partial.extend_from_slice(data);
+        let end = match self.partial.iter().rposition(|b| *b == b'\n') {
+            Some(i) => i + 1,
+            None => return,
+        };
+        for line in String::from_utf8_lossy(&self.partial[..end]).lines() {
+            on_line(line);
+        }
+        self.partial.drain(..end);
+    }
+
+    /// Passes any trailing partial line to `on_line`, e.g. once the stream
+    /// hits EOF.
+    pub fn finish(&mut self, on_line: &mut dyn FnMut(&str)) {
+        if !self.partial.is_empty() {
+            for line in String::from_utf8_lossy(&self.partial).lines() {
+                on_line(line);
+            }
+            self.partial.clear();
+        }
+    }
+}
+
 #[cfg(unix)]
 mod imp {
     use libc;
@@ -177,3 +214,52 @@ mod imp {
         slice::from_raw_parts_mut(v.as_mut_ptr().add(v.len()), v.capacity() - v.len())
     }
 }
+
+#[test]
+fn line_buffer_reassembles_lines() {
+    let mut lines = Vec::new();
+    let mut buffer = LineBuffer::new();
+    {
+        let mut on_line = |line: &str| lines.push(line.to_string());
+        buffer.push(b"hel", &mut on_line);
+        buffer.push(b"lo\nwor", &mut on_line);
+        buffer.push(b"ld\r\n\nfoo", &mut on_line);
+        buffer.push(b" bar", &mut on_line);
+        buffer.finish(&mut on_line);
+        buffer.finish(&mut on_line);
+    }
+    assert_eq!(lines, ["hello", "world", "", "foo bar"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn read2_captures_both_streams() {
+    use std::process::{Command, Stdio};
+
+    // Enough output on each stream to fill a pipe buffer, so reading them
+    // one after the other would deadlock.
+    let script = "i=0; while [ $i -lt 2000 ]; do \
+                  echo \"out $i\"; echo \"err $i\" >&2; i=$((i+1)); done";
+    let mut child = Command::new("sh")
+        .args(["-c", script])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let (mut out, mut err) = (Vec::new(), Vec::new());
+    read2(
+        child.stdout.take().unwrap(),
+        child.stderr.take().unwrap(),
+        &mut |is_out, data, _eof| {
+            let dst = if is_out { &mut out } else { &mut err };
+            dst.extend_from_slice(data);
+            data.clear();
+        },
+    )
+    .unwrap();
+    assert!(child.wait().unwrap().success());
+
+    let expected = |name: &str| (0..2000).map(|i| format!("{} {}\n", name, i)).collect::<String>();
+    assert_eq!(String::from_utf8(out).unwrap(), expected("out"));
+    assert_eq!(String::from_utf8(err).unwrap(), expected("err"));
+}
@@ -1,9 +1,11 @@
+use std::fs::File;
+use std::io::Read;
 use std::path::Path;
 
 use serde::de::Deserialize;
 
 use crate::util::config::Config;
-use crate::util::errors::NianjiaResult;
+use crate::util::errors::{NianjiaResult, NianjiaResultExt};
 
 pub fn parse(toml: &str, file: &Path, config: &Config) -> NianjiaResult<toml::Value> {
     let first_error = match toml.parse() {
@@ -11,11 +13,92 @@ pub fn parse(toml: &str, file: &Path, config: &Config) -> NianjiaResult<toml::Va
         Err(e) => e,
     };
 
-    let mut second_parser = toml::de::Deserializer::new(toml);
-    second_parser.set_require_newline_after_table(false);
-    if let Ok(ret) = toml::Value::deserialize(&mut second_parser) {
-        let msg = format!(
-            "\
+    for (lenient, message) in LENIENT.iter() {
+        let mut parser = toml::de::Deserializer::new(toml);
+        lenient(&mut parser);
+        if let Ok(ret) = toml::Value::deserialize(&mut parser) {
+            crate::config_warn!(config, "{}", message(file))?;
+            return Ok(ret);
+        }
+    }
+
+    let context = match first_error.line_col() {
+        Some((line, col)) => format!(
+            "could not parse input as TOML at `{}:{}:{}`\n\n{}",
+            file.display(),
+            line + 1,
+            col + 1,
+            snippet(toml, line, col)
+        ),
+        None => "could not parse input as TOML".to_string(),
+    };
+    let first_error = failure::Error::from(first_error);
+    Err(first_error.context(context).into())
+}
+
+/// Shows the 0-based `line` of `toml` with a caret under byte `col`, like:
+///
+/// ```text
+///  3 | b = = 1
+///    |     ^
+/// ```
+fn snippet(toml: &str, line: usize, col: usize) -> String {
+    let source = toml.lines().nth(line).unwrap_or("");
+    let number = (line + 1).to_string();
+    let gutter = " ".repeat(number.len());
+    let offset = source.get(..col).map_or(col, |before| before.chars().count());
+    format!(
+        "{} | {}\n{} | {}^",
+        number,
+        source,
+        gutter,
+        " ".repeat(offset)
+    )
+}
+
+/// Reads the file at `path` and parses it, see `parse`, refusing files larger
+/// than `max_bytes` rather than reading them whole into memory.
+pub fn parse_from_path(path: &Path, config: &Config, max_bytes: u64) -> NianjiaResult<toml::Value> {
+    let file = File::open(path).chain_err(|| format!("failed to open `{}`", path.display()))?;
+    let mut bytes = Vec::new();
+    file.take(max_bytes.saturating_add(1))
+        .read_to_end(&mut bytes)
+        .chain_err(|| format!("failed to read `{}`", path.display()))?;
+    // Checked before decoding, as the cut may fall inside a character.
+    if bytes.len() as u64 > max_bytes {
+        failure::bail!(
+            "`{}` is larger than the limit of {} bytes",
+            path.display(),
+            max_bytes
+        );
+    }
+    let contents = String::from_utf8(bytes)
+        .chain_err(|| format!("`{}` is not valid UTF-8", path.display()))?;
+    parse(&contents, path, config)
+}
+
+/// A lenient parser setting and the warning for files that need it.
+type Lenient = (fn(&mut toml::de::Deserializer<'_>), fn(&Path) -> String);
+
+/// The lenient parser settings tried in turn when a document doesn't parse.
+const LENIENT: [Lenient; 2] = [
+    (allow_missing_newline, missing_newline_message),
+    (allow_duplicate_table, duplicate_table_message),
+];
+
+#[allow(deprecated)] // Accepting these files is the point.
+fn allow_missing_newline(parser: &mut toml::de::Deserializer<'_>) {
+    parser.set_require_newline_after_table(false);
+}
+
+#[allow(deprecated)]
+fn allow_duplicate_table(parser: &mut toml::de::Deserializer<'_>) {
+    parser.set_allow_duplicate_after_longer_table(true);
+}
+
+fn missing_newline_message(file: &Path) -> String {
+    format!(
+        "\
 TOML file found which contains invalid syntax and will soon not parse
 at `{}`.
 
@@ -23,17 +106,13 @@ The TOML spec requires newlines after table definitions (e.g., `[a] b = 1` is
 invalid), but this file has a table header which does not have a newline after
 it. A newline needs to be added and this warning will soon become a hard error
 in the future.",
-            file.display()
-        );
-        config.shell().warn(&msg)?;
-        return Ok(ret);
-    }
+        file.display()
+    )
+}
 
-    let mut third_parser = toml::de::Deserializer::new(toml);
-    third_parser.set_allow_duplicate_after_longer_table(true);
-    if let Ok(ret) = toml::Value::deserialize(&mut third_parser) {
-        let msg = format!(
-            "\
+fn duplicate_table_message(file: &Path) -> String {
+    format!(
+        "\
 TOML file found which contains invalid syntax and will soon not parse
 at `{}`.
 
@@ -41,12 +120,136 @@ The TOML spec requires that each table header is defined at most once, but
 historical versions of NIANJIA have erroneously accepted this file. The table
 definitions will need to be merged together with one table header to proceed,
 and this will become a hard error in the future.",
-            file.display()
-        );
-        config.shell().warn(&msg)?;
-        return Ok(ret);
+        file.display()
+    )
+}
+
+/// How serious a `Diagnostic` is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    /// The document was accepted anyway.
+    Warning,
+    /// The document couldn't be parsed.
+    Error,
+}
+
+/// A problem found by `parse_recovering`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    /// The 1-based line and column, if known.
+    pub line_col: Option<(usize, usize)>,
+    pub severity: Severity,
+}
+
+impl Diagnostic {
+    fn new(message: String, error: &toml::de::Error, severity: Severity) -> Diagnostic {
+        Diagnostic {
+            message,
+            line_col: error.line_col().map(|(line, col)| (line + 1, col + 1)),
+            severity,
+        }
     }
+}
 
-    let first_error = failure::Error::from(first_error);
-    Err(first_error.context("could not parse input as TOML").into())
+/// Like `parse`, but reports every problem found as a `Diagnostic` instead
+/// of printing warnings or failing on the first error.
+///
+/// Syntax that `parse` accepts with a warning gives a value and a warning.
+/// When a lenient parser gets past the first error only to fail later, both
+/// the recoverable warning and the fatal error are reported.
+pub fn parse_recovering(toml: &str, file: &Path) -> (Option<toml::Value>, Vec<Diagnostic>) {
+    let first_error = match toml.parse() {
+        Ok(ret) => return (Some(ret), Vec::new()),
+        Err(e) => e,
+    };
+
+    for (lenient, message) in LENIENT.iter() {
+        let mut parser = toml::de::Deserializer::new(toml);
+        lenient(&mut parser);
+        let warning = || Diagnostic::new(message(file), &first_error, Severity::Warning);
+        match toml::Value::deserialize(&mut parser) {
+            Ok(ret) => return (Some(ret), vec![warning()]),
+            Err(ref e) if e.line_col() != first_error.line_col() => {
+                return (None, vec![warning(), Diagnostic::new(e.to_string(), e, Severity::Error)]);
+            }
+            Err(_) => {}
+        }
+    }
+
+    let error = Diagnostic::new(first_error.to_string(), &first_error, Severity::Error);
+    (None, vec![error])
+}
+
+#[test]
+fn parse_recovering_diagnostics() {
+    let file = Path::new("config");
+
+    let (value, diagnostics) = parse_recovering("[a] b = 1\n", file);
+    assert_eq!(value.unwrap()["a"]["b"].as_integer(), Some(1));
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].severity, Severity::Warning);
+    assert_eq!(diagnostics[0].line_col, Some((1, 5)));
+
+    let (value, diagnostics) = parse_recovering("[a] b = 1\nc = = 2\n", file);
+    assert!(value.is_none());
+    let found = diagnostics
+        .iter()
+        .map(|d| (d.severity, d.line_col))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        found,
+        [(Severity::Warning, Some((1, 5))), (Severity::Error, Some((2, 5)))]
+    );
+    assert!(diagnostics[0].message.contains("newlines after table definitions"));
+}
+
+#[test]
+fn parse_error_snippet() {
+    let config = Config::new(
+        crate::core::shell::Shell::from_write(Box::new(std::io::sink())),
+        std::env::temp_dir(),
+        crate::util::flock::Filesystem::new(std::env::temp_dir()),
+    );
+    let toml = "[build]\njobs = 2\ntarget = = \"x\"\n";
+    let err = parse(toml, Path::new("config"), &config).unwrap_err().to_string();
+    assert_eq!(
+        err.lines().collect::<Vec<_>>(),
+        [
+            "could not parse input as TOML at `config:3:10`",
+            "",
+            "3 | target = = \"x\"",
+            "  |          ^",
+        ]
+    );
+}
+
+#[test]
+fn parse_from_path_limit() {
+    let dir = crate::util::config::test_root("parse_from_path_limit");
+    let config = Config::new(
+        crate::core::shell::Shell::from_write(Box::new(std::io::sink())),
+        dir.join("cwd"),
+        crate::util::flock::Filesystem::new(dir.join("home")),
+    );
+    let path = dir.join("config.toml");
+    let contents = "[build]\njobs = 2\n";
+    std::fs::write(&path, contents).unwrap();
+    let len = contents.len() as u64;
+
+    let value = parse_from_path(&path, &config, len).unwrap();
+    assert_eq!(value["build"]["jobs"].as_integer(), Some(2));
+
+    let err = parse_from_path(&path, &config, len - 1).unwrap_err().to_string();
+    assert_eq!(
+        err,
+        format!("`{}` is larger than the limit of {} bytes", path.display(), len - 1)
+    );
+
+    // Reading one byte past the limit stops inside the two bytes of `é`.
+    let contents = "name = \"é\"\n";
+    std::fs::write(&path, contents).unwrap();
+    let limit = contents.find('é').unwrap() as u64;
+    let err = parse_from_path(&path, &config, limit).unwrap_err().to_string();
+    assert!(err.contains("is larger than the limit"), "{}", err);
 }
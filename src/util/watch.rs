@@ -0,0 +1,207 @@
+//! Waiting for configuration files to change, see `Config::watch`.
+//!
+//! Files are compared by their modification time and size. By default they
+//! are polled, which works the same on every platform. With the `notify`
+//! feature on Linux, the watcher instead sleeps until inotify reports activity
+//! in the files' directories, and only falls back to polling if inotify can't
+//! be set up.
+
+use std::fs;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Watches a fixed set of files, such as `Config::loaded_config_files`.
+pub struct FileWatcher {
+    files: Vec<(PathBuf, Option<Stamp>)>,
+    poll: Duration,
+    debounce: Duration,
+    events: imp::Events,
+}
+
+/// What is compared to notice a change. A missing file has no stamp.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Stamp {
+    modified: SystemTime,
+    len: u64,
+}
+
+impl FileWatcher {
+    /// Watches `files`, checking every 250ms and reporting a change once the
+    /// files have been left alone for 500ms.
+    pub fn new(files: Vec<PathBuf>) -> FileWatcher {
+        FileWatcher::with_intervals(files, Duration::from_millis(250), Duration::from_millis(500))
+    }
+
+    /// Watches `files`, checking every `poll` and reporting a change once
+    /// the files have been left alone for `debounce`, so that an editor
+    /// saving a file in several steps causes a single change.
+    ///
+    /// The files are armed once this returns: any later change is seen.
+    pub fn with_intervals(files: Vec<PathBuf>, poll: Duration, debounce: Duration) -> FileWatcher {
+        // Watch before taking the stamps, so that no change falls between.
+        let events = imp::Events::new(&files);
+        FileWatcher {
+            files: files
+                .into_iter()
+                .map(|path| {
+                    let stamp = stamp(&path);
+                    (path, stamp)
+                })
+                .collect(),
+            poll,
+            debounce,
+            events,
+        }
+    }
+
+    /// Blocks until a watched file is modified, created or removed, or
+    /// `timeout` elapses. Returns whether a change was seen.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> bool {
+        let start = Instant::now();
+        loop {
+            if self.changed() {
+                break;
+            }
+            let pause = match timeout {
+                Some(timeout) if start.elapsed() >= timeout => return false,
+                Some(timeout) => self.poll.min(timeout - start.elapsed()),
+                None => self.poll,
+            };
+            self.events.pause(pause);
+        }
+        let mut quiet_since = Instant::now();
+        while quiet_since.elapsed() < self.debounce {
+            self.events.pause(self.poll.min(self.debounce - quiet_since.elapsed()));
+            if self.changed() {
+                quiet_since = Instant::now();
+            }
+        }
+        true
+    }
+
+    /// Calls `on_change` after every change, see `wait`, until it returns
+    /// `ControlFlow::Break`.
+    pub fn watch(&mut self, mut on_change: impl FnMut() -> ControlFlow<()>) {
+        while self.wait(None) {
+            if on_change().is_break() {
+                break;
+            }
+        }
+    }
+
+    /// Records the current stamps, returning whether any differed.
+    fn changed(&mut self) -> bool {
+        let mut changed = false;
+        for (path, old) in &mut self.files {
+            let new = stamp(path);
+            if new != *old {
+                *old = new;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+fn stamp(path: &Path) -> Option<Stamp> {
+    let meta = fs::metadata(path).ok()?;
+    Some(Stamp {
+        modified: meta.modified().ok()?,
+        len: meta.len(),
+    })
+}
+
+#[cfg(all(feature = "notify", target_os = "linux"))]
+mod imp {
+    use std::collections::HashSet;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use std::time::Duration;
+
+    /// An inotify instance watching the directories of the files, so that
+    /// files replaced by a rename or created later are noticed too.
+    pub struct Events {
+        fd: Option<libc::c_int>,
+    }
+
+    impl Events {
+        pub fn new(files: &[PathBuf]) -> Events {
+            let dirs = files
+                .iter()
+                .filter_map(|file| file.parent())
+                .collect::<HashSet<&Path>>();
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+            if fd < 0 {
+                return Events { fd: None };
+            }
+            let mask = libc::IN_MODIFY
+                | libc::IN_ATTRIB
+                | libc::IN_CLOSE_WRITE
+                | libc::IN_CREATE
+                | libc::IN_DELETE
+                | libc::IN_MOVED_FROM
+                | libc::IN_MOVED_TO;
+            for dir in dirs {
+                let watched = CString::new(dir.as_os_str().as_bytes())
+                    .map(|dir| unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), mask) });
+                if !matches!(watched, Ok(wd) if wd >= 0) {
+                    unsafe { libc::close(fd) };
+                    return Events { fd: None };
+                }
+            }
+            Events { fd: Some(fd) }
+        }
+
+        /// Sleeps for `timeout`, or until something happens in a watched
+        /// directory.
+        pub fn pause(&mut self, timeout: Duration) {
+            let fd = match self.fd {
+                Some(fd) => fd,
+                None => return thread::sleep(timeout),
+            };
+            let mut pollfd = libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let millis = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+            if unsafe { libc::poll(&mut pollfd, 1, millis) } <= 0 {
+                return;
+            }
+            // The events only say that something happened; the stamps tell
+            // whether it was one of the files.
+            let mut buf = [0u8; 4096];
+            while unsafe { libc::read(fd, buf.as_mut_ptr() as *mut _, buf.len()) } > 0 {}
+        }
+    }
+
+    impl Drop for Events {
+        fn drop(&mut self) {
+            if let Some(fd) = self.fd {
+                unsafe { libc::close(fd) };
+            }
+        }
+    }
+}
+
+#[cfg(not(all(feature = "notify", target_os = "linux")))]
+mod imp {
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+
+    pub struct Events;
+
+    impl Events {
+        pub fn new(_files: &[PathBuf]) -> Events {
+            Events
+        }
+
+        pub fn pause(&mut self, timeout: Duration) {
+            thread::sleep(timeout);
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! Runs the `nianjia` binary against a fake `nianjia-hello` subcommand.
+
+#![cfg(unix)]
+
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn root(name: &str) -> PathBuf {
+    let root = env::temp_dir()
+        .join("nianjia-tests")
+        .join(format!("{}-{}", name, std::process::id()));
+    drop(fs::remove_dir_all(&root));
+    fs::create_dir_all(root.join("bin")).unwrap();
+    let hello = root.join("bin").join("nianjia-hello");
+    fs::write(&hello, "#!/bin/sh\necho \"hello $* from $NIANJIA\"\nexit 3\n").unwrap();
+    fs::set_permissions(&hello, fs::Permissions::from_mode(0o755)).unwrap();
+    root
+}
+
+fn nianjia(root: &PathBuf, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_nianjia"))
+        .args(args)
+        .current_dir(root)
+        .env_clear()
+        .env("PATH", root.join("bin"))
+        .env("HOME", root)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn runs_external_subcommand() {
+    let root = root("runs_external_subcommand");
+    let output = nianjia(&root, &["hello", "a", "b"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("hello hello a b from /"), "{}", stdout);
+    assert!(stdout.trim_end().ends_with("nianjia"), "{}", stdout);
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[test]
+fn suggests_closest_subcommand() {
+    let root = root("suggests_closest_subcommand");
+    let output = nianjia(&root, &["helo"]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("no such subcommand: `helo`"), "{}", stderr);
+    assert!(stderr.contains("Did you mean `hello`?"), "{}", stderr);
+    assert_eq!(output.status.code(), Some(101));
+}
+
+#[test]
+fn warns_without_configured_environment() {
+    let root = root("warns_without_configured_environment");
+    fs::create_dir_all(root.join(".nianjia")).unwrap();
+    fs::write(root.join(".nianjia").join("config"), "[env]\nGREETING = 1\n").unwrap();
+    let output = nianjia(&root, &["hello", "a"]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("running `nianjia hello` without the configured environment: "),
+        "{}",
+        stderr
+    );
+    assert_eq!(output.status.code(), Some(3));
+}